@@ -1,5 +1,11 @@
 use failure::{bail, Fallible};
+use lazy_static::lazy_static;
 use std::process::Command;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref REDACTED_ENV: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
 
 pub(crate) trait CommandRunExt {
     fn run(&mut self) -> Fallible<()>;
@@ -9,8 +15,65 @@ impl CommandRunExt for Command {
     fn run(&mut self) -> Fallible<()> {
         let r = self.status()?;
         if !r.success() {
-            bail!("Child [{:?}] exited: {}", self, r);
+            bail!("Child [{}] exited: {}", describe(self), r);
         }
         Ok(())
     }
 }
+
+/// Hide the values of environment variables whose names match these
+/// globs when showing commands, e.g. in errors.
+pub(crate) fn set_redacted_env(patterns: &[String]) {
+    *REDACTED_ENV.lock().unwrap() = patterns.to_vec();
+}
+
+/// Match `name` against a glob supporting `*` and `?`.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Returns `NAME=VALUE` with the value masked if the name is redacted.
+fn redact_var(var: &str, patterns: &[String]) -> String {
+    let mut parts = var.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let name_chars: Vec<char> = name.chars().collect();
+    let redacted = patterns.iter().any(|p| {
+        let p: Vec<char> = p.chars().collect();
+        glob_match(&p, &name_chars)
+    });
+    match parts.next() {
+        Some(_) if redacted => format!("{}=***", name),
+        _ => var.to_string(),
+    }
+}
+
+/// Format a command for diagnostics, redacting variables set on it and
+/// `--env=NAME=VALUE` arguments, as passed to podman.
+pub(crate) fn describe(cmd: &Command) -> String {
+    let patterns = REDACTED_ENV.lock().unwrap();
+    let mut words = Vec::new();
+    for (k, v) in cmd.get_envs() {
+        if let Some(v) = v {
+            let var = format!("{}={}", k.to_string_lossy(), v.to_string_lossy());
+            words.push(format!("{:?}", redact_var(&var, &patterns)));
+        }
+    }
+    words.push(format!("{:?}", cmd.get_program()));
+    for arg in cmd.get_args() {
+        let arg = arg.to_string_lossy();
+        let arg = match arg.strip_prefix("--env=") {
+            Some(var) => format!("--env={}", redact_var(var, &patterns)),
+            None => arg.into_owned(),
+        };
+        words.push(format!("{:?}", arg));
+    }
+    words.join(" ")
+}
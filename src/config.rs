@@ -0,0 +1,57 @@
+use failure::{bail, Fallible, ResultExt};
+use std::path::Path;
+
+/// User configuration.  The file uses a subset of TOML: `key = "value"`
+/// lines, with `#` comments.
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    /// Pull policy for `create` when `--pull` isn't given
+    pub default_pull: Option<String>,
+    /// Default for `gc --max-age`
+    pub auto_remove_age: Option<String>,
+}
+
+fn parse_value(v: &str) -> Fallible<String> {
+    let v = v.trim();
+    if v.len() >= 2 && v.starts_with('"') && v.ends_with('"') {
+        Ok(v[1..v.len() - 1].to_string())
+    } else {
+        bail!("Expected a quoted string, found: {}", v)
+    }
+}
+
+fn parse(contents: &str) -> Fallible<Config> {
+    let mut config = Config::default();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        || -> Fallible<()> {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = match parts.next() {
+                Some(v) => parse_value(v)?,
+                None => bail!("Expected key = \"value\""),
+            };
+            match key {
+                "default_pull" => config.default_pull = Some(value),
+                "auto_remove_age" => config.auto_remove_age = Some(value),
+                _ => bail!("Unknown key: {}", key),
+            }
+            Ok(())
+        }()
+        .with_context(|e| format!("line {}: {}", i + 1, e))?;
+    }
+    Ok(config)
+}
+
+/// Load the configuration file; a missing file is the same as an empty one.
+pub(crate) fn load(path: &Path) -> Fallible<Config> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(parse(&contents).with_context(|e| format!("Parsing {:?}: {}", path, e))?)
+}
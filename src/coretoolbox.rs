@@ -4,25 +4,64 @@ use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::io::prelude::*;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::process::CommandExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::Duration;
 use structopt::StructOpt;
 
 mod cmdrunext;
+mod config;
 mod podman;
 use cmdrunext::CommandRunExt;
 
 static DEFAULT_IMAGE: &str = "registry.fedoraproject.org/f30/fedora-toolbox:30";
 /// The label set on toolbox images and containers.
 static TOOLBOX_LABEL: &str = "com.coreos.toolbox";
+/// The value of `TOOLBOX_LABEL` on containers we create, bumped when what
+/// `create` sets up changes.  Older containers have `true`, i.e. version 0.
+static LABEL_SCHEMA_VERSION: u32 = 1;
+/// Set on images committed by `create --from-running` to the source container
+static CLONE_LABEL: &str = "com.coreos.toolbox.clone-of";
+/// Where `create --backup` commits the old container
+static BACKUP_REPOSITORY: &str = "localhost/coretoolbox-backup";
+/// Where `create --from-running` commits the source container
+static CLONE_REPOSITORY: &str = "localhost/coretoolbox-clone";
+/// Set on containers created with `--no-init`
+static NO_INIT_LABEL: &str = "com.coreos.toolbox.no-init";
+/// Set by `create --pin` to the digest of the image the container uses
+static IMAGE_DIGEST_LABEL: &str = "com.coreos.toolbox.image-digest";
+/// Set on containers `gc --auto` may remove once stopped and old enough
+static AUTO_REMOVE_LABEL: &str = "com.coreos.toolbox.auto-remove";
+/// Default for `gc --max-age`
+static DEFAULT_AUTO_REMOVE_AGE: &str = "7d";
+/// Set on an image or container to choose the default user for `run`;
+/// `root` means the same as `run --as-userns-root`.
+static DEFAULT_USER_LABEL: &str = "com.coreos.toolbox.default-user";
 /// The label set on github.com/debarshiray/fedora-toolbox images and containers.
 static D_TOOLBOX_LABEL: &str = "com.github.debarshiray.toolbox";
+/// Transports for using images from local archives rather than a registry
+static ARCHIVE_TRANSPORTS: &[&str] = &["oci-archive:", "docker-archive:"];
+/// Values for `--error-format`
+static ERROR_FORMATS: &[&str] = &["text", "json"];
+/// Values for `create --pull`
+static PULL_POLICIES: &[&str] = &["always", "missing", "never", "newer"];
+/// The default container hostname
+static DEFAULT_HOSTNAME: &str = "toolbox";
 /// The default container name
 static DEFAULT_NAME: &str = "coreos-toolbox";
+/// Environment variable overriding `DEFAULT_NAME`
+static NAME_ENV: &str = "CORETOOLBOX_NAME";
+/// Environment variable which, if non-empty, makes prompting an error
+static NONINTERACTIVE_ENV: &str = "CORETOOLBOX_NONINTERACTIVE";
 /// The path to our binary inside the container
 static USR_BIN_SELF: &str = "/usr/bin/coretoolbox";
 static STATE_ENV: &str = "TOOLBOX_STATE";
+/// Environment variable set at create time holding the serialized `ContainerConfig`
+static CONFIG_ENV: &str = "TOOLBOX_CONFIG";
+/// Environment variable set in toolbox sessions to the container name
+static TOOLBOX_NAME_ENV: &str = "TOOLBOX_NAME";
 
 lazy_static! {
     static ref APPDIRS: directories::ProjectDirs =
@@ -31,9 +70,24 @@ lazy_static! {
 
 static MAX_UID_COUNT: u32 = 65536;
 
+/// Host directories mounted in the container under /host.
+static HOST_MOUNTS: &[&str] = &["/dev", "/usr", "/var", "/etc", "/run", "/tmp"];
+/// Like `HOST_MOUNTS`, but only mounted if present.
+static OPTIONAL_HOST_MOUNTS: &[&str] = &["/sysroot"];
+
 /// Set of statically known paths to files/directories
 /// that we redirect inside the container to /host.
 static STATIC_HOST_FORWARDS: &[&str] = &["/run/dbus", "/run/libvirt", "/tmp", "/var/tmp"];
+/// Subset of `STATIC_HOST_FORWARDS` giving access to host system services,
+/// disabled with `create --no-dbus`.
+static SYSTEM_BUS_FORWARDS: &[&str] = &["/run/dbus", "/run/libvirt"];
+/// Journal sockets and directories forwarded for privileged toolboxes; the
+/// persistent journal is under `/var/log`, the volatile one under `/run/log`.
+static JOURNAL_FORWARDS: &[&str] = &[
+    "/run/systemd/journal",
+    "/run/log/journal",
+    "/var/log/journal",
+];
 /// Set of devices we forward (if they exist)
 static FORWARDED_DEVICES: &[&str] = &["bus", "dri", "kvm", "fuse"];
 
@@ -65,10 +119,50 @@ struct CreateOpts {
     /// Use a different base image
     image: Option<String>,
 
+    #[structopt(long = "containerfile", conflicts_with = "image")]
+    /// Build the base image from this Containerfile
+    containerfile: Option<String>,
+
+    #[structopt(
+        long = "rootfs",
+        conflicts_with = "image",
+        conflicts_with = "containerfile"
+    )]
+    /// Use an extracted root filesystem directory instead of an image.
+    /// The toolbox binary is still bind-mounted into it.
+    rootfs: Option<String>,
+
+    #[structopt(
+        long = "from-running",
+        conflicts_with = "image",
+        conflicts_with = "containerfile",
+        conflicts_with = "rootfs"
+    )]
+    /// Clone an existing toolbox: commit it to an image and use that as the base
+    from_running: Option<String>,
+
+    #[structopt(long = "pull", raw(possible_values = "PULL_POLICIES"))]
+    /// When to pull the image; `newer` requires podman 3.0 or newer.
+    /// Defaults to `default_pull` in the config file, or `missing`.
+    pull: Option<String>,
+
+    #[structopt(long = "pull-timeout")]
+    /// Give up pulling the image after this many seconds
+    pull_timeout: Option<u64>,
+
+    #[structopt(long = "pin", conflicts_with = "rootfs")]
+    /// Record the digest of the image in the container, shown by `list`
+    pin: bool,
+
     #[structopt(short = "n", long = "name")]
     /// Name the container
     name: Option<String>,
 
+    #[structopt(long = "name-prefix", conflicts_with = "name")]
+    /// Name the container PREFIX-N using the first free N; commands use
+    /// that name by default until it's removed
+    name_prefix: Option<String>,
+
     #[structopt(short = "N", long = "nested")]
     /// Allow running inside a container
     nested: bool,
@@ -76,6 +170,242 @@ struct CreateOpts {
     #[structopt(short = "D", long = "destroy")]
     /// Destroy any existing container
     destroy: bool,
+
+    #[structopt(long = "start", alias = "detach")]
+    /// Start and initialize the container after creating it, without entering it
+    start: bool,
+
+    #[structopt(long = "verify", conflicts_with = "no_init")]
+    /// Check that the container initializes, starting it and then stopping it
+    /// again unless --start is given
+    verify: bool,
+
+    #[structopt(
+        long = "keep-failed",
+        default_value = "true",
+        raw(possible_values = r#"&["true", "false"]"#),
+        parse(try_from_str)
+    )]
+    /// Keep the container if its initialization fails, for inspection;
+    /// with `false`, it's removed if that happens during --start or --verify
+    keep_failed: bool,
+
+    #[structopt(long = "non-interactive")]
+    /// Fail rather than prompting for input, e.g. for the image
+    /// (also set by a non-empty CORETOOLBOX_NONINTERACTIVE)
+    non_interactive: bool,
+
+    #[structopt(long = "backup", requires = "destroy")]
+    /// Before destroying the existing container, commit it to an image
+    /// tagged `localhost/coretoolbox-backup:NAME-TIMESTAMP`
+    backup: bool,
+
+    #[structopt(
+        long = "podman-arg",
+        raw(allow_hyphen_values = "true", number_of_values = "1")
+    )]
+    /// Extra argument passed through to `podman create`; may be repeated.
+    /// These are not validated and may conflict with the toolbox defaults.
+    podman_args: Vec<String>,
+
+    #[structopt(long = "host-resolver")]
+    /// Use the host's nsswitch.conf and resolv.conf for name resolution
+    host_resolver: bool,
+
+    #[structopt(long = "cwd")]
+    /// Mount the current directory into the container and start sessions there
+    cwd: bool,
+
+    #[structopt(long = "cidfile")]
+    /// Write the container ID to this file; it must not already exist
+    cidfile: Option<String>,
+
+    #[structopt(long = "profile-d")]
+    /// Shell snippet installed in /etc/profile.d, sourced by each shell in the toolbox
+    profile_d: Option<String>,
+
+    #[structopt(long = "bashrc")]
+    /// Shell snippet for interactive bash sessions in this toolbox only, run after ~/.bashrc
+    bashrc: Option<String>,
+
+    #[structopt(long = "exit-on-empty")]
+    /// Stop the container when the last session exits
+    exit_on_empty: bool,
+
+    #[structopt(long = "auto-remove")]
+    /// Let `gc --auto` remove the container once it's stopped and old enough
+    auto_remove: bool,
+
+    #[structopt(long = "security-opt", raw(number_of_values = "1"))]
+    /// Security option passed to podman, e.g. `seccomp=profile.json`; may be repeated.
+    /// Added to our defaults; a `label=` option replaces the default `label=disable`.
+    security_opts: Vec<String>,
+
+    #[structopt(long = "no-dbus")]
+    /// Don't give the container access to the host's system D-Bus and libvirt sockets.
+    /// This breaks e.g. `systemctl`, `flatpak-spawn --host`, polkit and libvirt system connections.
+    no_dbus: bool,
+
+    #[structopt(long = "group-add", raw(number_of_values = "1"))]
+    /// Add the container process to this group (name or gid), e.g. `kvm`; may be repeated
+    group_add: Vec<String>,
+
+    #[structopt(
+        long = "userns",
+        conflicts_with = "map_group_root",
+        parse(try_from_str = "parse_userns")
+    )]
+    /// `auto` or `auto:size=N`: let podman allocate a separate range of subordinate
+    /// ids for this toolbox, isolating it from others.  Your uid is then not mapped
+    /// to itself, so files in your home directory appear owned by nobody.
+    userns: Option<String>,
+
+    #[structopt(long = "map-group-root")]
+    /// Map your primary group on the host to gid 0 in the container, for sharing
+    /// group-owned directories.  Anything container root can do with its group
+    /// applies to your host group, and files owned by your group appear owned by
+    /// group root; the uid mapping is unchanged.  Unprivileged toolboxes only.
+    map_group_root: bool,
+
+    #[structopt(
+        long = "pids-limit",
+        raw(allow_hyphen_values = "true"),
+        parse(try_from_str = "parse_pids_limit")
+    )]
+    /// Limit the number of processes in the container; -1 for unlimited
+    pids_limit: Option<i64>,
+
+    #[structopt(long = "pid", parse(try_from_str = "parse_pid_mode"))]
+    /// PID namespace: `host`, `private` or `container:NAME`.  Privileged
+    /// toolboxes default to `host`, others to `private`.
+    pid: Option<String>,
+
+    #[structopt(long = "memory", parse(try_from_str = "parse_memory_size"))]
+    /// Memory limit, e.g. `4g`; suffixes b, k, m and g are accepted
+    memory: Option<String>,
+
+    #[structopt(
+        long = "memory-swap",
+        requires = "memory",
+        raw(allow_hyphen_values = "true"),
+        parse(try_from_str = "parse_memory_swap")
+    )]
+    /// Limit on memory plus swap, at least --memory; -1 for unlimited swap
+    memory_swap: Option<String>,
+
+    #[structopt(long = "cpuset-cpus", parse(try_from_str = "parse_cpuset"))]
+    /// CPUs the container may run on, e.g. `0-3,7`
+    cpuset_cpus: Option<String>,
+
+    #[structopt(long = "cpuset-mems", parse(try_from_str = "parse_cpuset"))]
+    /// NUMA memory nodes the container may use, e.g. `0,1`
+    cpuset_mems: Option<String>,
+
+    #[structopt(long = "cgroup-parent", parse(try_from_str = "parse_cgroup_parent"))]
+    /// Place the container under this cgroup, e.g. a systemd slice like `toolbox.slice`
+    cgroup_parent: Option<String>,
+
+    #[structopt(long = "no-init")]
+    /// Don't set up the container (user, forwards, etc.) on entry; `run` enters
+    /// the unmodified image, which is mostly useful for compatibility testing
+    no_init: bool,
+
+    #[structopt(
+        long = "entrypoint",
+        conflicts_with = "exit_on_empty",
+        conflicts_with = "stop_signal",
+        parse(try_from_str = "parse_entrypoint")
+    )]
+    /// Run this as pid 1 instead of coretoolbox's own, which reaps processes
+    /// and keeps the container running; `run` only works while it's running
+    entrypoint: Option<String>,
+
+    #[structopt(
+        long = "tmpfs",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_tmpfs")
+    )]
+    /// Mount a tmpfs at PATH[:OPTS], e.g. `/scratch:size=1g`; may be repeated
+    tmpfs: Vec<String>,
+
+    #[structopt(
+        long = "mount",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_mount")
+    )]
+    /// Add a mount using podman's --mount syntax, e.g.
+    /// `type=bind,source=/srv/data,target=/data,ro`; may be repeated
+    mounts: Vec<String>,
+
+    #[structopt(
+        long = "add-host",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_add_host")
+    )]
+    /// Add a HOST:IP entry to the container's /etc/hosts; may be repeated
+    add_hosts: Vec<String>,
+
+    #[structopt(
+        long = "forward-socket",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_forward_socket")
+    )]
+    /// Make a host Unix socket under /run, /tmp, /var etc. available at
+    /// the same path; may be repeated
+    forward_sockets: Vec<String>,
+
+    #[structopt(long = "secret", raw(number_of_values = "1"))]
+    /// Make a podman secret available at /run/secrets/ID, as ID[,OPTS].  With
+    /// `src=PATH` in OPTS, the secret is created from the file if it doesn't exist,
+    /// and otherwise must have the same contents.  May be repeated.
+    secrets: Vec<String>,
+
+    #[structopt(long = "kernel-debug")]
+    /// Mount the host /boot (kernel images, config, System.map) for tools like crash and perf
+    kernel_debug: bool,
+
+    #[structopt(long = "no-debugfs")]
+    /// Don't mount the host's /sys/kernel/debug in privileged toolboxes; tools
+    /// like bpftrace need it
+    no_debugfs: bool,
+
+    #[structopt(long = "no-media")]
+    /// Don't link /run/media to the host's, where udisks mounts removable media
+    no_media: bool,
+
+    #[structopt(long = "hostname")]
+    /// Hostname of the container (default: toolbox)
+    hostname: Option<String>,
+
+    #[structopt(long = "hostname-matches-host")]
+    /// Use the host's hostname for the container; overridden by --hostname
+    hostname_matches_host: bool,
+
+    #[structopt(long = "copy-passwd-entry")]
+    /// Copy your account from the host's /etc/passwd and /etc/group, keeping e.g.
+    /// the full name and shell, rather than creating a new one with useradd
+    copy_passwd_entry: bool,
+
+    #[structopt(long = "match-machine-id")]
+    /// Use the host's /etc/machine-id (read-only), so e.g. the journal and other
+    /// tools keyed on it agree with the host.  systemd in the container will then
+    /// believe it is the host.
+    match_machine_id: bool,
+
+    #[structopt(long = "read-only", conflicts_with = "no_tmpfs_run")]
+    /// Make the container's root filesystem read-only.  /etc and /var are copied
+    /// to a tmpfs on each start so setup can modify them; changes there are
+    /// lost when the container stops, and packages can't be installed.
+    read_only: bool,
+
+    #[structopt(long = "stop-signal", parse(try_from_str = "parse_stop_signal"))]
+    /// Signal used to stop the container, e.g. `SIGINT` (default: SIGTERM)
+    stop_signal: Option<String>,
+
+    #[structopt(long = "no-tmpfs-run")]
+    /// Keep /run in the container's filesystem instead of a tmpfs, so its contents
+    /// persist across restarts; runtime setup is still redone on each start
+    no_tmpfs_run: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -92,27 +422,212 @@ struct RunOpts {
     #[structopt(long)]
     /// Run as (user namespace) root, do not change to unprivileged uid
     as_userns_root: bool,
+
+    #[structopt(long, conflicts_with = "as_userns_root")]
+    /// Run as the unprivileged uid even if the toolbox defaults to root
+    as_user: bool,
+
+    #[structopt(
+        short = "u",
+        long,
+        conflicts_with = "as_userns_root",
+        conflicts_with = "as_user"
+    )]
+    /// Run as this user (name or uid) from the container's passwd, e.g. a
+    /// service account
+    user: Option<String>,
+
+    #[structopt(long)]
+    /// Don't forward host environment variables such as DISPLAY into the session
+    no_preserve_env: bool,
+
+    #[structopt(long)]
+    /// Start the session in the current directory, if it's visible in the toolbox
+    preserve_cwd: bool,
+
+    #[structopt(long, default_value = "120")]
+    /// Seconds to wait for a concurrent container initialization before giving up
+    entrypoint_timeout: u64,
+
+    #[structopt(short = "q", long)]
+    /// Don't show progress while the toolbox is set up
+    quiet: bool,
+
+    #[structopt(
+        long,
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_env_arg")
+    )]
+    /// Set an environment variable (KEY=VALUE) in the session; may be repeated
+    env: Vec<String>,
 }
 
 #[derive(Debug, StructOpt)]
 struct RmOpts {
-    #[structopt(short = "n", long = "name", default_value = "coreos-toolbox")]
+    #[structopt(short = "n", long = "name")]
     /// Name for container
-    name: String,
+    name: Option<String>,
+
+    #[structopt(short = "a", long = "all", conflicts_with = "name")]
+    /// Delete all toolbox containers
+    all: bool,
+
+    #[structopt(short = "f", long = "force")]
+    /// Don't ask for confirmation
+    force: bool,
+
+    #[structopt(short = "y", long = "yes")]
+    /// Same as --force
+    yes: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct RepairOpts {
+    #[structopt(short = "n", long = "name")]
+    /// Name of container
+    name: Option<String>,
+
+    #[structopt(long = "full")]
+    /// Also redo the one-time setup, e.g. the user account and sudo
+    full: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct ListToolboxImagesOpts {
+    #[structopt(long = "all-tags")]
+    /// Show every name an image is tagged with, not just the first
+    all_tags: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct GcOpts {
+    #[structopt(long = "auto")]
+    /// Also remove stopped toolboxes created with --auto-remove
+    auto: bool,
+
+    #[structopt(long = "max-age", parse(try_from_str = "parse_age"))]
+    /// With --auto, only remove toolboxes created longer ago than this, e.g.
+    /// 12h or 30d (default: auto_remove_age from the configuration, or 7d)
+    max_age: Option<u64>,
+
+    #[structopt(long = "dry-run")]
+    /// Print what would be removed without removing anything
+    dry_run: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct ListOpts {
+    #[structopt(long = "format")]
+    /// Format each container using a template, e.g. `{{.Name}} {{.Image}}`.
+    /// Available fields: Name, Image, Digest (with create --pin), Running, Created
+    format: Option<String>,
+
+    #[structopt(long = "running-only", conflicts_with = "stopped_only")]
+    /// Only list running containers
+    running_only: bool,
+
+    #[structopt(long = "stopped-only")]
+    /// Only list stopped containers
+    stopped_only: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct EnvSetOpts {
+    #[structopt(short = "n", long = "name")]
+    /// Name of container
+    name: Option<String>,
+
+    #[structopt(parse(try_from_str = "parse_env_arg"))]
+    /// Variables to set, as KEY=VALUE
+    vars: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct EnvUnsetOpts {
+    #[structopt(short = "n", long = "name")]
+    /// Name of container
+    name: Option<String>,
+
+    /// Names of variables to unset
+    keys: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct EnvListOpts {
+    #[structopt(short = "n", long = "name")]
+    /// Name of container
+    name: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum EnvCmd {
+    /// Set variables for every session in the toolbox
+    Set(EnvSetOpts),
+    /// Remove variables set with `env set`
+    Unset(EnvUnsetOpts),
+    /// Show variables set with `env set`
+    List(EnvListOpts),
+}
+
+#[derive(Debug, StructOpt)]
+struct VersionOpts {
+    #[structopt(long = "json")]
+    /// Output in JSON format
+    json: bool,
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "coretoolbox", about = "Toolbox")]
 #[structopt(rename_all = "kebab-case")]
-enum Opt {
+struct Opt {
+    #[structopt(long, raw(possible_values = "podman::LOG_LEVELS"))]
+    /// Log level for podman itself
+    podman_log_level: Option<String>,
+
+    #[structopt(long, default_value = "text", raw(possible_values = "ERROR_FORMATS"))]
+    /// How to print errors; `json` writes an object with message, causes and kind
+    error_format: String,
+
+    #[structopt(long, raw(number_of_values = "1"))]
+    /// Hide the values of environment variables matching this glob, e.g. `*TOKEN*`,
+    /// when showing commands in errors; may be repeated
+    redact_env: Vec<String>,
+
+    #[structopt(long)]
+    /// Run as root via sudo, e.g. for a privileged toolbox; the session
+    /// environment is kept
+    sudo: bool,
+
+    #[structopt(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Cmd {
     /// Create a toolbox
     Create(CreateOpts),
     /// Enter the toolbox
     Run(RunOpts),
     /// Delete the toolbox container
     Rm(RmOpts),
+    /// List toolbox containers
+    List(ListOpts),
+    /// Manage environment variables persisted for a toolbox
+    Env(EnvCmd),
+    /// Remove saved state for toolboxes that no longer exist
+    Gc(GcOpts),
+    /// Redo the setup of a toolbox, e.g. if a forward was broken
+    Repair(RepairOpts),
     /// Display names of already downloaded images with toolbox labels
-    ListToolboxImages,
+    ListToolboxImages(ListToolboxImagesOpts),
+    /// Check whether running toolboxes use an outdated coretoolbox binary
+    Update,
+    /// Display the host paths made available in toolboxes
+    ListForwards,
+    /// Display version information for coretoolbox and podman
+    Version(VersionOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -121,19 +636,44 @@ struct ExecOpts {
     #[structopt(long)]
     /// See run --as-userns-root
     as_userns_root: bool,
+
+    #[structopt(long, default_value = "120")]
+    /// See run --entrypoint-timeout
+    entrypoint_timeout: u64,
+
+    #[structopt(long)]
+    /// See run --quiet
+    quiet: bool,
+
+    #[structopt(long)]
+    /// Start in this directory rather than the one from create --cwd
+    cwd: Option<String>,
+
+    #[structopt(long, conflicts_with = "as_userns_root")]
+    /// See run --user
+    user: Option<String>,
+
+    #[structopt(long)]
+    /// The container is removed if initialization fails, see create --keep-failed
+    removing_failed: bool,
+
+    /// Command to run instead of an interactive shell
+    command: Vec<String>,
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 enum InternalOpt {
+    #[structopt(raw(setting = "structopt::clap::AppSettings::Hidden"))]
     /// Internal implementation detail; do not use
     RunPid1,
+    #[structopt(raw(setting = "structopt::clap::AppSettings::Hidden"))]
     /// Internal implementation detail; do not use
     Exec(ExecOpts),
 }
 
 fn get_toolbox_images() -> Fallible<Vec<podman::ImageInspect>> {
-    let label = format!("label={}=true", TOOLBOX_LABEL);
+    let label = format!("label={}", TOOLBOX_LABEL);
     let mut ret = podman::image_inspect(&["--filter", label.as_str()]).with_context(|e| {
         format!(
             r#"Finding containers with label "{}": {}"#,
@@ -149,17 +689,288 @@ fn get_toolbox_images() -> Fallible<Vec<podman::ImageInspect>> {
             )
         })?,
     );
-    Ok(ret.drain(..).filter(|p| p.names.is_some()).collect())
+    // These are committed from toolbox containers, so they have the label,
+    // but aren't images to create toolboxes from.
+    let committed = |name: &String| {
+        [BACKUP_REPOSITORY, CLONE_REPOSITORY]
+            .iter()
+            .any(|r| name.starts_with(&format!("{}:", r)))
+    };
+    Ok(ret
+        .drain(..)
+        .filter(|p| match p.names.as_ref() {
+            Some(names) => !names.iter().all(committed) || names.is_empty(),
+            None => false,
+        })
+        .collect())
+}
+
+/// The container name given on the command line, falling back to
+/// `$CORETOOLBOX_NAME` and then `DEFAULT_NAME`.
+fn container_name(name: Option<&String>) -> Fallible<String> {
+    choose_container_name(name, generated_name()?)
+}
+
+/// Like `container_name`, with the name recorded by `create --name-prefix`
+/// passed in.
+fn choose_container_name(name: Option<&String>, generated: Option<String>) -> Fallible<String> {
+    let name = if let Some(name) = name {
+        name.clone()
+    } else if std::env::var_os(NAME_ENV).is_some() {
+        getenv_required_utf8(NAME_ENV)?
+    } else if let Some(name) = generated {
+        name
+    } else {
+        DEFAULT_NAME.to_string()
+    };
+    validate_container_name(&name)?;
+    Ok(name)
+}
+
+/// File recording the last name generated by `create --name-prefix`,
+/// which is used when no name is given until that container is removed.
+fn generated_name_path() -> PathBuf {
+    APPDIRS.data_dir().join("generated-name")
+}
+
+fn generated_name() -> Fallible<Option<String>> {
+    match std::fs::read_to_string(generated_name_path()) {
+        Ok(s) if s.trim().is_empty() => Ok(None),
+        Ok(s) => Ok(Some(s.trim().to_string())),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn record_generated_name(name: &str) -> Fallible<()> {
+    let path = generated_name_path();
+    std::fs::create_dir_all(path.parent().expect("parent"))?;
+    std::fs::write(&path, format!("{}\n", name))
+        .with_context(|e| format!("Writing {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// Stop using `name` by default, if it was recorded, e.g. once it's removed.
+fn forget_generated_name(name: &str) -> Fallible<()> {
+    if generated_name()?.as_deref() == Some(name) {
+        std::fs::remove_file(generated_name_path())?;
+    }
+    Ok(())
+}
+
+/// Check a name against podman's rules, `[a-zA-Z0-9][a-zA-Z0-9_.-]*`, since
+/// its error for an invalid one is confusing.
+fn validate_container_name(name: &str) -> Fallible<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    if !valid {
+        bail!("Invalid container name {:?}: must start with a letter or digit, followed by letters, digits, '_', '.' or '-'", name);
+    }
+    Ok(())
+}
+
+/// Find the first free container name of the form PREFIX-N.
+fn generate_name(prefix: &str) -> Fallible<String> {
+    validate_container_name(prefix)?;
+    for i in 1.. {
+        let name = format!("{}-{}", prefix, i);
+        if !podman::has_object(podman::InspectType::Container, &name)? {
+            return Ok(name);
+        }
+    }
+    unreachable!()
+}
+
+/// Commit a container to a timestamped image in `repository`, returning its
+/// name.  `changes` are passed as `podman commit --change`.
+fn commit_container(name: &str, repository: &str, changes: &[String]) -> Fallible<String> {
+    let out = Command::new("date")
+        .args(&["-u", "+%Y%m%d%H%M%S"])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        bail!("Failed to get the current time");
+    }
+    let timestamp = String::from_utf8(out.stdout)?;
+    let image = format!("{}:{}-{}", repository, name, timestamp.trim());
+    let mut c = podman::cmd();
+    c.arg("commit");
+    for change in changes {
+        c.arg(format!("--change={}", change));
+    }
+    c.args(&[name, &image])
+        .stdout(Stdio::null())
+        .run()
+        .with_context(|e| format!("Committing {}: {}", name, e))?;
+    Ok(image)
+}
+
+/// An image committed for `create`, removed when dropped unless kept,
+/// so a failed `create` doesn't leave it behind.
+struct TransientImage(Option<String>);
+
+impl TransientImage {
+    fn name(&self) -> &str {
+        self.0.as_ref().expect("image").as_str()
+    }
+
+    /// Keep the image, e.g. once a container uses it.
+    fn keep(mut self) {
+        self.0.take();
+    }
+}
+
+impl Drop for TransientImage {
+    fn drop(&mut self) {
+        if let Some(image) = self.0.take() {
+            if let Err(e) = podman::cmd()
+                .args(&["rmi", image.as_str()])
+                .stdout(Stdio::null())
+                .run()
+            {
+                eprintln!("warning: Failed to remove {}: {}", image, e);
+            }
+        }
+    }
 }
 
-/// Pull a container image if not present
-fn ensure_image(name: &str) -> Fallible<()> {
-    if !podman::has_object(podman::InspectType::Image, name)? {
-        podman::cmd().args(&["pull", name]).run()?;
+/// Pull a container image according to the pull policy; `newer`
+/// is treated like `missing`.  See `podman::pull` for `progress` and `timeout`.
+fn ensure_image(
+    name: &str,
+    policy: &str,
+    progress: Option<&mut dyn FnMut(podman::PullProgress)>,
+    timeout: Option<Duration>,
+) -> Fallible<()> {
+    let present = podman::has_object(podman::InspectType::Image, name)?;
+    match policy {
+        "always" => podman::pull(name, progress, timeout)?,
+        "never" if !present => bail!("Image {} not found, and pull policy is never", name),
+        "missing" | "newer" if !present => podman::pull(name, progress, timeout)?,
+        _ => {}
+    }
+    // For `name@sha256:...`, make sure that's what we have
+    if let Some(idx) = name.rfind('@') {
+        let expected = &name[idx + 1..];
+        let digests = podman::image_digests(name)?;
+        if !digests.iter().any(|d| d == expected) {
+            bail!(
+                "Image {} has digest {}, expected {}",
+                name,
+                digests.first().map(|s| s.as_str()).unwrap_or("(none)"),
+                expected
+            );
+        }
     }
     Ok(())
 }
 
+/// Render pull progress as a single updating line on the terminal.
+fn pull_progress_printer(name: &str) -> impl FnMut(podman::PullProgress) + '_ {
+    let mut started: usize = 0;
+    let mut done = 0;
+    move |p| {
+        match p {
+            podman::PullProgress::BlobStarted(_) => started += 1,
+            podman::PullProgress::BlobDone(_) => done += 1,
+            podman::PullProgress::Config => {
+                eprintln!("\rPulling {}: {} layers done", name, started.max(done));
+                return;
+            }
+            podman::PullProgress::Other(line) => {
+                if !line.is_empty() {
+                    eprintln!("{}", line);
+                }
+                return;
+            }
+        }
+        let width = 30;
+        let filled = (width * done.min(started))
+            .checked_div(started)
+            .unwrap_or(0);
+        eprint!(
+            "\rPulling {}: [{}{}] {}/{} layers",
+            name,
+            "#".repeat(filled),
+            " ".repeat(width - filled),
+            done.min(started),
+            started
+        );
+    }
+}
+
+/// Compute the SHA-256 of a file, in hex
+fn sha256_file<P: AsRef<Path>>(path: P) -> Fallible<String> {
+    let path = path.as_ref();
+    let out = Command::new("sha256sum")
+        .arg(path)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        bail!("Failed to checksum {:?}", path);
+    }
+    let out = String::from_utf8(out.stdout)?;
+    Ok(out
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| failure::format_err!("Invalid sha256sum output: {}", out))?
+        .to_string())
+}
+
+/// Build a toolbox image from a Containerfile, returning its tag.  The tag
+/// is derived from the Containerfile contents so unchanged files aren't rebuilt.
+fn build_image(containerfile: &str) -> Fallible<String> {
+    let path = Path::new(containerfile);
+    let digest = sha256_file(path)?;
+    let tag = format!("localhost/coretoolbox-build:{}", &digest[..12]);
+    if podman::has_object(podman::InspectType::Image, &tag)? {
+        return Ok(tag);
+    }
+    let context = match path.parent() {
+        Some(p) if p != Path::new("") => p,
+        _ => Path::new("."),
+    };
+    podman::cmd()
+        .args(&["build", "--tag", &tag, "--file", containerfile])
+        .arg(format!("--label={}=true", TOOLBOX_LABEL))
+        .arg(context)
+        .run()?;
+    Ok(tag)
+}
+
+/// Load an image from an archive such as `oci-archive:/path/to/image.tar`,
+/// returning its name.  That's the reference stored in the archive if it
+/// has one, or otherwise one derived from the image ID.
+fn load_archive(spec: &str) -> Fallible<String> {
+    let out = podman::cmd()
+        .args(&["pull", spec])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        bail!("Failed to load image from {}", spec);
+    }
+    let out = String::from_utf8(out.stdout)?;
+    let id = out
+        .lines()
+        .rev()
+        .find(|l| !l.trim().is_empty())
+        .ok_or_else(|| failure::format_err!("No image ID loading {}", spec))?
+        .trim();
+    let filter = format!("id={}", id);
+    let image = podman::image_inspect(&["--filter", filter.as_str()])?
+        .into_iter()
+        .next()
+        .ok_or_else(|| failure::format_err!("Loaded image {} not found", id))?;
+    if let Some(name) = image.names.as_ref().and_then(|n| n.first()) {
+        return Ok(name.clone());
+    }
+    let short_id: String = image.id.chars().take(12).collect();
+    let tag = format!("localhost/archive-{}:latest", short_id);
+    podman::cmd().args(&["tag", id, &tag]).run()?;
+    Ok(tag)
+}
+
 /// Parse an extant environment variable as UTF-8
 fn getenv_required_utf8(n: &str) -> Fallible<String> {
     if let Some(v) = std::env::var_os(n) {
@@ -178,135 +989,1213 @@ struct EntrypointState {
     home: String,
 }
 
-fn append_preserved_env(c: &mut Command) -> Fallible<()> {
-    for n in PRESERVED_ENV.iter() {
-        let v = match std::env::var_os(n) {
-            Some(v) => v,
-            None => continue,
-        };
-        let v = v
-            .to_str()
-            .ok_or_else(|| failure::format_err!("{} contains invalid UTF-8", n))?;
-        c.arg(format!("--env={}={}", n, v));
-    }
-    Ok(())
+/// Configuration chosen at `create` time and persisted in the
+/// container's environment for use by the entrypoint.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+struct ContainerConfig {
+    /// Name of the container
+    name: String,
+    /// ID of the image the container was created from
+    image_id: String,
+    /// Use the host's name resolution configuration
+    host_resolver: bool,
+    /// Directory sessions start in
+    workdir: Option<String>,
+    /// Contents of a snippet to install in /etc/profile.d
+    profile_d: Option<String>,
+    /// Contents of a snippet sourced by interactive bash after ~/.bashrc
+    bashrc: Option<String>,
+    /// Exit pid1 once no sessions remain
+    exit_on_empty: bool,
+    /// Don't forward `SYSTEM_BUS_FORWARDS`
+    no_dbus: bool,
+    /// The root filesystem is read-only; see `entrypoint::make_writable`
+    read_only: bool,
+    /// Bind mount the host's /etc/machine-id
+    match_machine_id: bool,
+    /// Copy the user's entries from the host's passwd and group files
+    copy_passwd_entry: bool,
+    /// Signal on which pid1 exits, e.g. `SIGINT`; SIGTERM if unset
+    stop_signal: Option<String>,
+    /// Don't link /run/media to the host's
+    no_media: bool,
+    /// Pid 1 isn't `internals run-pid1`, from `create --entrypoint`
+    custom_entrypoint: bool,
+    /// Host sockets to symlink to via /host
+    forward_sockets: Vec<String>,
 }
 
-fn get_default_image() -> Fallible<String> {
-    let toolboxes = get_toolbox_images()?;
-    Ok(match toolboxes.len() {
-        0 => {
-            print!(
-                "Welcome to coretoolbox
-Enter a pull spec for toolbox image; default: {defimg}
-Image: ",
-                defimg = DEFAULT_IMAGE
-            );
-            std::io::stdout().flush()?;
-            let mut input = String::new();
-            std::io::stdin().read_line(&mut input)?;
-            input.truncate(input.trim_end().len());
-            if input.is_empty() {
-                DEFAULT_IMAGE.to_owned()
-            } else {
-                input
-            }
+impl ContainerConfig {
+    /// Load the configuration; containers created by older versions
+    /// don't have one, so use the defaults.
+    fn from_env() -> Fallible<Self> {
+        if std::env::var_os(CONFIG_ENV).is_none() {
+            return Ok(Default::default());
         }
-        1 => toolboxes[0].names.as_ref().unwrap()[0].clone(),
-        _ => bail!("Multiple toolbox images found, must specify via -I"),
-    })
+        Ok(serde_json::from_str(
+            getenv_required_utf8(CONFIG_ENV)?.as_str(),
+        )?)
+    }
 }
 
-/// Return the user's runtime directory, and create it if it doesn't exist.
-/// The latter behavior is mostly necessary for running `sudo`.
-fn get_ensure_runtime_dir() -> Fallible<String> {
-    let real_uid: u32 = nix::unistd::getuid().into();
-    let runtime_dir_val = std::env::var_os("XDG_RUNTIME_DIR");
-    Ok(match runtime_dir_val.as_ref() {
-        Some(d) => d
-            .to_str()
-            .ok_or_else(|| failure::format_err!("XDG_RUNTIME_DIR is invalid UTF-8"))?
-            .to_string(),
-        None => format!("/run/user/{}", real_uid),
-    })
+/// Load the user configuration file.
+fn load_config() -> Fallible<config::Config> {
+    config::load(&APPDIRS.config_dir().join("config.toml"))
 }
 
-fn create(opts: &CreateOpts) -> Fallible<()> {
-    if in_container() && !opts.nested {
-        bail!("Already inside a container");
+/// Directory holding host-side state associated with a container name.
+fn container_state_dir(name: &str) -> PathBuf {
+    APPDIRS.data_dir().join("containers").join(name)
+}
+
+/// Remove any host-side state left over from a previous container
+/// with the same name.
+fn clear_container_state(name: &str) -> Fallible<()> {
+    match std::fs::remove_dir_all(container_state_dir(name)) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
     }
+}
 
-    let image = if opts.image.is_none()
-        && opts.name.is_none()
-        && !podman::has_object(podman::InspectType::Container, DEFAULT_NAME)?
-    {
-        get_default_image()?
-    } else {
-        opts.image
-            .as_ref()
-            .map(|s| s.as_str())
-            .unwrap_or(DEFAULT_IMAGE)
-            .to_owned()
-    };
+/// XDG_RUNTIME_DIR is normally under /run, but may be anywhere; returns
+/// the directory to mount under /host for it if it's not in `HOST_MOUNTS`.
+fn runtime_dir_volume(runtime_dir: &str) -> Option<&str> {
+    let path = Path::new(runtime_dir);
+    if HOST_MOUNTS.iter().any(|m| path.starts_with(m)) {
+        return None;
+    }
+    // The parent, so it keeps working if the runtime dir is recreated
+    Some(match path.parent() {
+        Some(p) if p != Path::new("/") => p.to_str().expect("runtime dir is UTF-8"),
+        _ => runtime_dir,
+    })
+}
 
-    let name = opts
-        .name
-        .as_ref()
-        .map(|s| s.as_str())
-        .unwrap_or(DEFAULT_NAME);
+/// Returns true if the host path is made visible at the same path inside
+/// the container by our default mounts and forwards.
+fn path_is_forwarded(p: &Path) -> bool {
+    entrypoint::DATADIRS
+        .iter()
+        .chain(STATIC_HOST_FORWARDS.iter())
+        .any(|d| p.starts_with(d) || p.starts_with(format!("/var{}", d)))
+}
 
-    if opts.destroy {
+/// Where a host path is visible in a `--no-init` container, which has
+/// none of the forwards: only under /host via our host mounts.
+fn no_init_path(p: &Path) -> Option<PathBuf> {
+    HOST_MOUNTS
+        .iter()
+        .chain(OPTIONAL_HOST_MOUNTS.iter())
+        .find(|d| p.starts_with(d))
+        .map(|_| Path::new("/host").join(p.strip_prefix("/").expect("absolute")))
+}
+
+/// Validate an `--entrypoint` argument: an absolute path, or a command
+/// name looked up in the container's PATH.
+fn parse_entrypoint(s: &str) -> Result<String, String> {
+    let valid = if s.starts_with('/') {
+        !s.contains(char::is_whitespace)
+    } else {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.+-".contains(c))
+    };
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "Expected an absolute path or a command name, found: {}",
+            s
+        ))
+    }
+}
+
+/// Validate a `--pid` argument.
+fn parse_pid_mode(s: &str) -> Result<String, String> {
+    let valid = match s {
+        "host" | "private" => true,
+        _ => s
+            .strip_prefix("container:")
+            .is_some_and(|name| validate_container_name(name).is_ok()),
+    };
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "Expected host, private or container:NAME, found: {}",
+            s
+        ))
+    }
+}
+
+/// Validate a `--pids-limit` argument: a positive integer, or -1 for unlimited.
+fn parse_pids_limit(s: &str) -> Result<i64, String> {
+    match s.parse::<i64>() {
+        Ok(n) if n > 0 || n == -1 => Ok(n),
+        _ => Err(format!("Expected a positive integer or -1, found: {}", s)),
+    }
+}
+
+/// Parse a size like podman's `--memory`: a number with an optional
+/// b, k, m or g suffix.
+fn memory_size_bytes(s: &str) -> Option<u64> {
+    let lower = s.to_ascii_lowercase();
+    let (num, mult) = match lower.chars().last()? {
+        'b' => (&lower[..lower.len() - 1], 1),
+        'k' => (&lower[..lower.len() - 1], 1 << 10),
+        'm' => (&lower[..lower.len() - 1], 1 << 20),
+        'g' => (&lower[..lower.len() - 1], 1 << 30),
+        _ => (lower.as_str(), 1),
+    };
+    num.parse::<u64>().ok()?.checked_mul(mult)
+}
+
+fn parse_memory_size(s: &str) -> Result<String, String> {
+    match memory_size_bytes(s) {
+        Some(n) if n > 0 => Ok(s.to_string()),
+        _ => Err(format!("Expected a size like 512m or 4g, found: {}", s)),
+    }
+}
+
+/// Validate a cpuset list: comma-separated numbers and ranges like `0-3,7`.
+fn parse_cpuset(s: &str) -> Result<String, String> {
+    let valid = s.split(',').all(|item| {
+        let mut bounds = item.splitn(2, '-').map(|n| n.parse::<u32>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(_)), None) => true,
+            (Some(Ok(lo)), Some(Ok(hi))) => lo <= hi,
+            _ => false,
+        }
+    });
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("Expected a list like 0-3,7, found: {}", s))
+    }
+}
+
+/// Parse an age like `90s`, `30m`, `12h` or `7d` into seconds.
+fn parse_age(s: &str) -> Result<u64, String> {
+    let (num, mult) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 60 * 60),
+        Some('d') => (&s[..s.len() - 1], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| format!("Expected an age like 12h or 7d, found: {}", s))
+}
+
+fn parse_memory_swap(s: &str) -> Result<String, String> {
+    if s == "-1" {
+        return Ok(s.to_string());
+    }
+    parse_memory_size(s)
+}
+
+/// Parse a `--stop-signal` argument such as `INT`, `SIGINT` or `2` into
+/// the signal's name.
+fn parse_stop_signal(s: &str) -> Result<String, String> {
+    use nix::sys::signal::Signal;
+    use std::str::FromStr;
+    let sig = match s.parse::<i32>() {
+        Ok(n) => Signal::from_c_int(n).ok(),
+        Err(_) if s.starts_with("SIG") => Signal::from_str(s).ok(),
+        Err(_) => Signal::from_str(&format!("SIG{}", s)).ok(),
+    };
+    match sig {
+        // These can't be handled, or are used for other purposes by pid1
+        Some(Signal::SIGKILL)
+        | Some(Signal::SIGSTOP)
+        | Some(Signal::SIGILL)
+        | Some(Signal::SIGFPE)
+        | Some(Signal::SIGSEGV)
+        | Some(Signal::SIGCHLD) => Err(format!("Unsupported stop signal: {}", s)),
+        Some(sig) => Ok(format!("{:?}", sig)),
+        None => Err(format!("Unknown signal: {}", s)),
+    }
+}
+
+/// Validate a `--cgroup-parent` argument: a systemd slice name such as
+/// `toolbox.slice`, or an absolute cgroupfs path.
+fn parse_cgroup_parent(s: &str) -> Result<String, String> {
+    let valid = if s.starts_with('/') {
+        !s.split('/').any(|c| c == "." || c == "..")
+    } else {
+        s.ends_with(".slice") && s.len() > ".slice".len() && !s.contains('/')
+    };
+    if !valid || s.contains(char::is_whitespace) {
+        return Err(format!(
+            "Expected a slice name like toolbox.slice or an absolute cgroup path, found: {}",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Lightly validate a `--mount` argument; podman checks the rest.
+fn parse_mount(s: &str) -> Result<String, String> {
+    if !s.split(',').any(|o| o.starts_with("type=")) {
+        return Err(format!("Mount must specify type=, found: {}", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Validate a `--userns` argument: `auto`, optionally with `:size=N`.
+fn parse_userns(s: &str) -> Result<String, String> {
+    let valid = match s.strip_prefix("auto") {
+        Some("") => true,
+        Some(rest) => rest
+            .strip_prefix(":size=")
+            .map(|n| n.parse::<u32>().map(|n| n > 0).unwrap_or(false))
+            .unwrap_or(false),
+        None => false,
+    };
+    if !valid {
+        return Err(format!("Expected auto or auto:size=N, found: {}", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Validate a `--forward-socket` argument: an absolute path visible under /host.
+fn parse_forward_socket(s: &str) -> Result<String, String> {
+    let p = Path::new(s);
+    if !p.is_absolute() || p.components().any(|c| c == std::path::Component::ParentDir) {
+        return Err(format!("Expected an absolute path, found: {}", s));
+    }
+    if !HOST_MOUNTS
+        .iter()
+        .any(|m| p.starts_with(m) && p != Path::new(m))
+    {
+        return Err(format!(
+            "{} is not under one of {}; use --volume instead",
+            s,
+            HOST_MOUNTS.join(", ")
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Validate an `--add-host` argument: `HOST:IP`, where IP may be IPv6.
+fn parse_add_host(s: &str) -> Result<String, String> {
+    let mut parts = s.splitn(2, ':');
+    let host = parts.next().unwrap_or("");
+    let ip = parts.next().unwrap_or("");
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(format!("Expected HOST:IP, found: {}", s));
+    }
+    if ip.parse::<std::net::IpAddr>().is_err() {
+        return Err(format!("Invalid IP address in {}: {}", s, ip));
+    }
+    Ok(s.to_string())
+}
+
+/// Validate a `--tmpfs` argument: an absolute path, optionally followed by `:OPTS`.
+fn parse_tmpfs(s: &str) -> Result<String, String> {
+    let path = s.split(':').next().unwrap_or("");
+    if !path.starts_with('/') {
+        return Err(format!("tmpfs path must be absolute: {}", path));
+    }
+    Ok(s.to_string())
+}
+
+/// Validate a `KEY=VALUE` environment variable argument.  Values are
+/// saved one per line, so they can't contain newlines.
+fn parse_env_arg(s: &str) -> Result<String, String> {
+    if s.contains('\n') {
+        return Err(format!(
+            "Environment variables can't contain newlines: {:?}",
+            s
+        ));
+    }
+    match s.find('=') {
+        Some(0) | None => Err(format!("Expected KEY=VALUE, found: {}", s)),
+        Some(i) if s[..i].contains(char::is_whitespace) => {
+            Err(format!("Invalid environment variable name: {}", &s[..i]))
+        }
+        Some(_) => Ok(s.to_string()),
+    }
+}
+
+/// File holding the variables from `env set`, as KEY=VALUE lines.
+fn container_env_path(name: &str) -> PathBuf {
+    container_state_dir(name).join("env")
+}
+
+fn read_container_env(name: &str) -> Fallible<Vec<String>> {
+    match std::fs::read_to_string(container_env_path(name)) {
+        Ok(s) => Ok(s
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_container_env(name: &str, vars: &[String]) -> Fallible<()> {
+    let path = container_env_path(name);
+    std::fs::create_dir_all(path.parent().expect("env parent"))?;
+    let mut contents = vars.join("\n");
+    contents.push('\n');
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Returns the KEY of a KEY=VALUE pair.
+fn env_key(var: &str) -> &str {
+    var.split_once('=').map_or(var, |(key, _)| key)
+}
+
+fn env(cmd: &EnvCmd) -> Fallible<()> {
+    let name = match cmd {
+        EnvCmd::Set(opts) => container_name(opts.name.as_ref())?,
+        EnvCmd::Unset(opts) => container_name(opts.name.as_ref())?,
+        EnvCmd::List(opts) => container_name(opts.name.as_ref())?,
+    };
+    if !podman::has_object(podman::InspectType::Container, &name)? {
+        bail!("No toolbox container '{}' found", name);
+    }
+    let mut vars = read_container_env(&name)?;
+    match cmd {
+        EnvCmd::Set(opts) => {
+            for v in opts.vars.iter() {
+                vars.retain(|e| env_key(e) != env_key(v));
+                vars.push(v.clone());
+            }
+            write_container_env(&name, &vars)?;
+        }
+        EnvCmd::Unset(opts) => {
+            vars.retain(|e| !opts.keys.iter().any(|k| k == env_key(e)));
+            write_container_env(&name, &vars)?;
+        }
+        EnvCmd::List(_) => {
+            for v in vars.iter() {
+                println!("{}", v);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn append_preserved_env(c: &mut Command) -> Fallible<()> {
+    for e in preserved_env()? {
+        c.arg(format!("--env={}", e));
+    }
+    Ok(())
+}
+
+/// The set `PRESERVED_ENV` variables, as `NAME=VALUE`.
+fn preserved_env() -> Fallible<Vec<String>> {
+    let mut r = Vec::new();
+    for n in PRESERVED_ENV.iter() {
+        let v = match std::env::var_os(n) {
+            Some(v) => v,
+            None => continue,
+        };
+        let v = v
+            .to_str()
+            .ok_or_else(|| failure::format_err!("{} contains invalid UTF-8", n))?;
+        r.push(format!("{}={}", n, v));
+    }
+    Ok(r)
+}
+
+fn get_default_image(interactive: bool) -> Fallible<String> {
+    let toolboxes = get_toolbox_images()?;
+    Ok(match toolboxes.len() {
+        0 if !interactive => {
+            bail!("No image specified and interactive input disabled; use -I")
+        }
+        0 => {
+            print!(
+                "Welcome to coretoolbox
+Enter a pull spec for toolbox image; default: {defimg}
+Image: ",
+                defimg = DEFAULT_IMAGE
+            );
+            std::io::stdout().flush()?;
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            input.truncate(input.trim_end().len());
+            if input.is_empty() {
+                DEFAULT_IMAGE.to_owned()
+            } else {
+                input
+            }
+        }
+        1 => toolboxes[0].name().to_string(),
+        _ => bail!("Multiple toolbox images found, must specify via -I"),
+    })
+}
+
+/// Return the user's runtime directory, and create it if it doesn't exist.
+/// The latter behavior is mostly necessary for running `sudo`.
+fn get_ensure_runtime_dir() -> Fallible<String> {
+    let real_uid: u32 = nix::unistd::getuid().into();
+    let runtime_dir_val = std::env::var_os("XDG_RUNTIME_DIR");
+    Ok(match runtime_dir_val.as_ref() {
+        Some(d) => d
+            .to_str()
+            .ok_or_else(|| failure::format_err!("XDG_RUNTIME_DIR is invalid UTF-8"))?
+            .to_string(),
+        None => format!("/run/user/{}", real_uid),
+    })
+}
+
+/// `entrypoint::INIT_JOBS_ENV` as `NAME=VALUE`, if set.  It's passed to each
+/// session rather than preserved at create time, so unsetting it takes effect.
+fn init_jobs_env() -> Fallible<Option<String>> {
+    if std::env::var_os(entrypoint::INIT_JOBS_ENV).is_none() {
+        return Ok(None);
+    }
+    let jobs = getenv_required_utf8(entrypoint::INIT_JOBS_ENV)?;
+    Ok(Some(format!("{}={}", entrypoint::INIT_JOBS_ENV, jobs)))
+}
+
+/// Execute ourself again via `sudo` with the same arguments, keeping the
+/// variables we forward into the container.
+fn reexec_sudo(args: &[String]) -> Fallible<()> {
+    let self_bin = get_self_bin()?;
+    let mut cmd = Command::new("sudo");
+    // sudo sets USER to root, which is what we want as root
+    let mut keep: Vec<&str> = PRESERVED_ENV
+        .iter()
+        .filter(|n| **n != "USER" && std::env::var_os(n).is_some())
+        .cloned()
+        .collect();
+    if std::env::var_os(entrypoint::INIT_JOBS_ENV).is_some() {
+        keep.push(entrypoint::INIT_JOBS_ENV);
+    }
+    if std::env::var_os("XDG_RUNTIME_DIR").is_none() {
+        cmd.env("XDG_RUNTIME_DIR", get_ensure_runtime_dir()?);
+        keep.push("XDG_RUNTIME_DIR");
+    }
+    if !keep.is_empty() {
+        cmd.arg(format!("--preserve-env={}", keep.join(",")));
+    }
+    // We ignore --sudo when already root, so pass the arguments as is
+    cmd.arg("--").arg(self_bin).args(&args[1..]);
+    Err(failure::format_err!("Executing sudo: {}", cmd.exec()))
+}
+
+/// We bind mount our own binary and host directories into the container,
+/// which only works when podman runs containers on this machine.
+fn ensure_local_podman(info: &podman::Info) -> Fallible<()> {
+    if info.remote {
+        bail!("podman is configured to use a remote service (e.g. `podman machine`); coretoolbox requires podman to run containers locally on the host");
+    }
+    Ok(())
+}
+
+/// Returns the subset of `paths` which are private mounts set up by systemd's
+/// `PrivateTmp=`, detected by their source being a `systemd-private-*` directory.
+fn find_private_tmp<'a>(paths: &[&'a str]) -> Fallible<Vec<&'a str>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(paths
+        .iter()
+        .filter(|p| {
+            mountinfo.lines().any(|line| {
+                // Fields: ID parent-ID major:minor root mount-point ...
+                let mut fields = line.split_whitespace().skip(3);
+                match (fields.next(), fields.next()) {
+                    (Some(root), Some(mountpoint)) => {
+                        mountpoint == **p && root.contains("/systemd-private-")
+                    }
+                    _ => false,
+                }
+            })
+        })
+        .cloned()
+        .collect())
+}
+
+/// Find a path to our own binary which can be bind mounted into the container.
+/// If the binary can't be found, e.g. because it was deleted during an upgrade
+/// or `/proc/self/exe` can't be resolved, fall back to a copy in the cache directory.
+fn get_self_bin() -> Fallible<PathBuf> {
+    let candidates = vec![
+        std::fs::read_link("/proc/self/exe").ok(),
+        std::env::current_exe().ok(),
+        std::env::args_os()
+            .next()
+            .and_then(find_in_path)
+            .and_then(|p| p.canonicalize().ok()),
+    ];
+    if let Some(p) = candidates.into_iter().flatten().find(|p| p.is_file()) {
+        return Ok(p);
+    }
+    || -> Fallible<PathBuf> {
+        let cachedir = APPDIRS.cache_dir();
+        std::fs::create_dir_all(cachedir)?;
+        let dest = cachedir.join("coretoolbox");
+        let tmp = tempfile::NamedTempFile::new_in(cachedir)?;
+        std::io::copy(
+            &mut std::fs::File::open("/proc/self/exe")?,
+            &mut tmp.as_file(),
+        )?;
+        tmp.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o755))?;
+        tmp.persist(&dest)?;
+        Ok(dest)
+    }()
+    .with_context(|e| format!("Failed to find or copy our own binary: {}", e))
+    .map_err(|e| e.into())
+}
+
+/// Fail early if the kernel doesn't allow unprivileged user namespaces,
+/// rather than letting podman fail obscurely.
+fn check_userns() -> Fallible<()> {
+    fn read_sysctl(path: &str) -> Option<String> {
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|s| s.trim().to_string())
+    }
+    // Debian-specific; absent elsewhere
+    if read_sysctl("/proc/sys/kernel/unprivileged_userns_clone").as_deref() == Some("0") {
+        bail!("Unprivileged user namespaces are disabled; enable them with `sudo sysctl kernel.unprivileged_userns_clone=1`");
+    }
+    if read_sysctl("/proc/sys/user/max_user_namespaces").as_deref() == Some("0") {
+        bail!("User namespaces are disabled; enable them with e.g. `sudo sysctl user.max_user_namespaces=15000`");
+    }
+    Ok(())
+}
+
+/// Check that unprivileged containers can work, returning the number of
+/// ids to map in the user namespace.
+fn unprivileged_id_count(real_uid: u32) -> Fallible<u32> {
+    check_userns()?;
+    let username = getenv_required_utf8("USER")?;
+    let subuids = subid_count("/etc/subuid", &username, real_uid)?;
+    let subgids = subid_count("/etc/subgid", &username, real_uid)?;
+    for (path, count) in &[("/etc/subuid", subuids), ("/etc/subgid", subgids)] {
+        if *count == 0 {
+            bail!(
+                "No entry for {} in {}; add one with e.g. `sudo usermod --add-subuids 100000-165535 --add-subgids 100000-165535 {}`",
+                username,
+                path,
+                username
+            );
+        }
+    }
+    let id_count = subuids.min(subgids).min(MAX_UID_COUNT);
+    if real_uid >= id_count {
+        bail!(
+            "Your uid {} does not fit in the {} subordinate ids delegated to you",
+            real_uid,
+            id_count
+        );
+    }
+    Ok(id_count)
+}
+
+/// Returns 2 if the host uses the unified cgroup hierarchy, otherwise 1.
+fn cgroups_version() -> u32 {
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        2
+    } else {
+        1
+    }
+}
+
+/// Resource limits need cgroup delegation, which rootless podman only has
+/// with cgroups v2; fail clearly rather than letting podman fail cryptically.
+/// Also checks the limits are consistent.
+fn check_resource_limits(opts: &CreateOpts, privileged: bool) -> Fallible<()> {
+    if let (Some(m), Some(swap)) = (opts.memory.as_ref(), opts.memory_swap.as_ref()) {
+        if let Some(swap_bytes) = memory_size_bytes(swap) {
+            if swap_bytes < memory_size_bytes(m).expect("validated memory") {
+                bail!("--memory-swap {} is less than --memory {}", swap, m);
+            }
+        }
+    }
+    if privileged {
+        return Ok(());
+    }
+    if cgroups_version() == 2 {
+        return check_cpuset_delegated(opts);
+    }
+    let requested: Vec<&str> = [
+        ("--memory", opts.memory.is_some()),
+        ("--memory-swap", opts.memory_swap.is_some()),
+        ("--pids-limit", opts.pids_limit.is_some()),
+        ("--cpuset-cpus", opts.cpuset_cpus.is_some()),
+        ("--cpuset-mems", opts.cpuset_mems.is_some()),
+    ]
+    .iter()
+    .filter(|(_, set)| *set)
+    .map(|(flag, _)| *flag)
+    .collect();
+    if !requested.is_empty() {
+        bail!(
+            "{} requires cgroups v2 for unprivileged toolboxes; this host uses cgroups v1",
+            requested.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// systemd doesn't delegate the cpuset controller to users by default.
+fn check_cpuset_delegated(opts: &CreateOpts) -> Fallible<()> {
+    if opts.cpuset_cpus.is_none() && opts.cpuset_mems.is_none() {
+        return Ok(());
+    }
+    let uid = nix::unistd::getuid();
+    let path = format!(
+        "/sys/fs/cgroup/user.slice/user-{uid}.slice/user@{uid}.service/cgroup.controllers",
+        uid = uid
+    );
+    // If we can't tell, let podman try
+    let controllers = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(_) => return Ok(()),
+    };
+    if !controllers.split_whitespace().any(|c| c == "cpuset") {
+        bail!("--cpuset-cpus and --cpuset-mems need the cpuset cgroup controller, which isn't delegated to your user; see `Delegate=` in systemd.resource-control(5)");
+    }
+    Ok(())
+}
+
+/// Returns the number of subordinate ids delegated to the user in `path`
+/// (`/etc/subuid` or `/etc/subgid`), which may be zero.
+fn subid_count(path: &str, username: &str, uid: u32) -> Fallible<u32> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let uidstr = uid.to_string();
+    let mut count = 0u32;
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        if fields.len() != 3 || (fields[0] != username && fields[0] != uidstr) {
+            continue;
+        }
+        let n = fields[2]
+            .parse::<u32>()
+            .with_context(|e| format!("Parsing {}: {}", path, e))?;
+        count = count.saturating_add(n);
+    }
+    Ok(count)
+}
+
+/// The host's hostname, or `DEFAULT_HOSTNAME` if it isn't meaningful.
+fn host_hostname() -> Fallible<String> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")?;
+    let hostname = hostname.trim();
+    match hostname {
+        "" | "localhost" | "localhost.localdomain" | "(none)" => {
+            eprintln!(
+                "warning: host has no hostname set; using {}",
+                DEFAULT_HOSTNAME
+            );
+            Ok(DEFAULT_HOSTNAME.to_string())
+        }
+        h => Ok(h.to_string()),
+    }
+}
+
+/// Returns true if the running kernel is at least `major.minor`.
+fn kernel_at_least(major: u32, minor: u32) -> bool {
+    let uts = nix::sys::utsname::uname();
+    let mut parts = uts
+        .release()
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|p| p.parse::<u32>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(ma)), Some(Ok(mi))) => (ma, mi) >= (major, minor),
+        _ => false,
+    }
+}
+
+/// Rootless overlay storage needs fuse-overlayfs unless the kernel allows
+/// unprivileged overlay mounts (5.13).  Returns a description of the
+/// problem, if any; it may still work, e.g. if podman is configured
+/// with another mount program.
+fn check_rootless_storage(info: &podman::Info) -> Option<String> {
+    let problem = match info.storage_driver.as_str() {
+        "overlay" if kernel_at_least(5, 13) => None,
+        "overlay" if find_in_path("fuse-overlayfs").is_none() => Some(
+            "rootless overlay storage needs fuse-overlayfs on kernels before 5.13; install it (e.g. `dnf install fuse-overlayfs`)",
+        ),
+        "overlay" if !Path::new("/dev/fuse").exists() => {
+            Some("fuse-overlayfs needs /dev/fuse; load the fuse module (`modprobe fuse`)")
+        }
+        "vfs" => Some(
+            "podman is using the vfs storage driver, which copies every layer; install fuse-overlayfs and run `podman system reset` to use overlay",
+        ),
+        _ => None,
+    };
+    problem.map(String::from)
+}
+
+/// Returns true if SELinux is enabled and enforcing on the host.
+fn selinux_enforcing() -> bool {
+    std::fs::read_to_string("/sys/fs/selinux/enforce")
+        .map(|s| s.trim() == "1")
+        .unwrap_or(false)
+}
+
+/// Secrets created from `src=` for `create`, removed when dropped unless
+/// kept, so a failed `create` doesn't leave them behind.
+#[derive(Default)]
+struct TransientSecrets(Vec<String>);
+
+impl TransientSecrets {
+    /// Keep the secrets, e.g. once a container uses them.
+    fn keep(mut self) {
+        self.0.clear();
+    }
+}
+
+impl Drop for TransientSecrets {
+    fn drop(&mut self) {
+        for id in self.0.drain(..) {
+            if let Err(e) = podman::cmd()
+                .args(&["secret", "rm", id.as_str()])
+                .stdout(Stdio::null())
+                .run()
+            {
+                eprintln!("warning: Failed to remove secret {}: {}", id, e);
+            }
+        }
+    }
+}
+
+/// A validated `--secret` argument.
+struct SecretSpec {
+    id: String,
+    /// The argument to pass to podman
+    arg: String,
+    /// The file to create the secret from, if it doesn't exist yet
+    create_from: Option<String>,
+}
+
+/// Parse and validate a `--secret` argument; an existing secret must
+/// have the same contents as `src=`.  Nothing is created yet, so this
+/// can run before e.g. destroying an existing container.
+fn resolve_secret(spec: &str) -> Fallible<SecretSpec> {
+    let mut parts = spec.split(',');
+    let id = parts.next().unwrap_or("");
+    if id.is_empty() {
+        bail!("Invalid secret: {}", spec);
+    }
+    let mut src = None;
+    let mut podman_opts = vec![id];
+    for opt in parts {
+        if let Some(path) = opt.strip_prefix("src=") {
+            src = Some(path);
+        } else {
+            podman_opts.push(opt);
+        }
+    }
+    if let Some(src) = src {
+        if !Path::new(src).is_file() {
+            bail!("Secret source {} not found", src);
+        }
+    }
+    let create_from = match (podman::has_secret(id)?, src) {
+        (true, Some(src)) => {
+            let contents =
+                std::fs::read(src).with_context(|e| format!("Reading {}: {}", src, e))?;
+            if podman::secret_data(id)?.as_ref() != Some(&contents) {
+                bail!(
+                    "Secret {} already exists and may differ from {}; remove it with `podman secret rm {}` to recreate it",
+                    id,
+                    src,
+                    id
+                );
+            }
+            None
+        }
+        (true, None) => None,
+        (false, Some(src)) => Some(src.to_string()),
+        (false, None) => bail!("Secret {} not found; specify src=PATH to create it", id),
+    };
+    Ok(SecretSpec {
+        id: id.to_string(),
+        arg: podman_opts.join(","),
+        create_from,
+    })
+}
+
+/// Create the secrets from `src=` that don't exist yet, recording them
+/// in `created`.
+fn create_secrets(secrets: &[SecretSpec], created: &mut TransientSecrets) -> Fallible<()> {
+    for secret in secrets.iter() {
+        if let Some(src) = secret.create_from.as_ref() {
+            podman::cmd()
+                .args(&["secret", "create", secret.id.as_str(), src.as_str()])
+                .stdout(Stdio::null())
+                .run()?;
+            created.0.push(secret.id.clone());
+        }
+    }
+    Ok(())
+}
+
+fn create(opts: &CreateOpts) -> Fallible<()> {
+    ensure_not_nested(opts.nested)?;
+    let info = podman::preflight()?;
+    ensure_local_podman(&info)?;
+
+    let real_uid: u32 = nix::unistd::getuid().into();
+    let privileged = real_uid == 0;
+    // Check these before doing anything, e.g. destroying an existing container
+    check_resource_limits(opts, privileged)?;
+    if opts.userns.is_some() && !podman::version_at_least(2, 0)? {
+        bail!("--userns requires podman 2.0 or newer");
+    }
+    let id_count = if privileged {
+        None
+    } else {
+        Some(unprivileged_id_count(real_uid)?)
+    };
+    if opts.map_group_root && opts.userns.is_none() && id_count.is_none() {
+        bail!("--map-group-root is only supported for unprivileged toolboxes");
+    }
+    let storage_problem = if privileged {
+        None
+    } else {
+        check_rootless_storage(&info)
+    };
+    if let Some(problem) = storage_problem.as_ref() {
+        eprintln!("warning: {}", problem);
+    }
+    let secrets = opts
+        .secrets
+        .iter()
+        .map(|s| resolve_secret(s))
+        .collect::<Fallible<Vec<_>>>()?;
+    let kernel_debug_boot = if opts.kernel_debug {
+        // On ostree hosts /boot may not be mounted, but the content is in the sysroot
+        let boot = ["/boot", "/sysroot/boot"]
+            .iter()
+            .find(|d| {
+                std::fs::read_dir(d)
+                    .map(|mut e| e.next().is_some())
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| failure::err_msg("--kernel-debug: No /boot content found"))?;
+        Some(boot)
+    } else {
+        None
+    };
+
+    let name = match opts.name_prefix.as_ref() {
+        Some(prefix) => generate_name(prefix)?,
+        None => container_name(opts.name.as_ref())?,
+    };
+    // Recreating the recorded container, e.g. with --destroy, keeps it the default
+    let record_name = opts.name_prefix.is_some() || generated_name()?.as_deref() == Some(&name);
+
+    let rootfs = match opts.rootfs.as_ref() {
+        Some(rootfs) => {
+            let path = Path::new(rootfs);
+            if !path.join("usr").is_dir() {
+                bail!("{} is not a root filesystem (missing /usr)", rootfs);
+            }
+            Some(
+                path.canonicalize()?
+                    .to_str()
+                    .ok_or_else(|| failure::err_msg("non-UTF8 rootfs path"))?
+                    .to_string(),
+            )
+        }
+        None => None,
+    };
+
+    let clone_image = match opts.from_running.as_ref() {
+        Some(src) => {
+            if !podman::has_object(podman::InspectType::Container, src)? {
+                bail!("No container '{}' found", src);
+            }
+            // Labels set by `create` on the source container end up on the
+            // image, and from there on the clone; reset them.
+            let mut changes = vec![format!("LABEL={}={}", CLONE_LABEL, src)];
+            for label in &[AUTO_REMOVE_LABEL, NO_INIT_LABEL, IMAGE_DIGEST_LABEL] {
+                changes.push(format!("LABEL={}=", label));
+            }
+            Some(TransientImage(Some(commit_container(
+                src,
+                CLONE_REPOSITORY,
+                &changes,
+            )?)))
+        }
+        None => None,
+    };
+
+    let image = if rootfs.is_some() {
+        None
+    } else if let Some(clone_image) = clone_image.as_ref() {
+        Some(clone_image.name().to_string())
+    } else if let Some(containerfile) = opts.containerfile.as_ref() {
+        Some(build_image(containerfile)?)
+    } else if opts.image.is_none()
+        && opts.name.is_none()
+        && !podman::has_object(podman::InspectType::Container, &name)?
+    {
+        Some(get_default_image(
+            !(opts.non_interactive || interactive_disabled()),
+        )?)
+    } else {
+        Some(
+            opts.image
+                .as_ref()
+                .map(|s| s.as_str())
+                .unwrap_or(DEFAULT_IMAGE)
+                .to_owned(),
+        )
+    };
+
+    if opts.destroy {
+        if opts.backup && podman::has_object(podman::InspectType::Container, &name)? {
+            let backup = commit_container(&name, BACKUP_REPOSITORY, &[])?;
+            println!("Saved {} as {}", name, backup);
+        }
         rm(&RmOpts {
-            name: name.to_owned(),
+            name: Some(name.clone()),
+            all: false,
+            force: true,
+            yes: false,
         })?;
     }
 
-    ensure_image(&image)?;
+    let pull = match opts.pull.as_ref() {
+        // Cloned and built images are only local
+        _ if clone_image.is_some() || opts.containerfile.is_some() => "missing".to_string(),
+        Some(p) => p.clone(),
+        None => load_config()?
+            .default_pull
+            .unwrap_or_else(|| "missing".to_string()),
+    };
+    if !PULL_POLICIES.contains(&pull.as_str()) {
+        bail!("Invalid pull policy: {}", pull);
+    }
+    // We need the image to pin its digest, and pulling only fetches what
+    // changed, like podman's `--pull=newer`.
+    let pull = if opts.pin && pull == "newer" {
+        "always".to_string()
+    } else {
+        pull
+    };
+    let is_archive = image
+        .as_ref()
+        .map(|image| ARCHIVE_TRANSPORTS.iter().any(|t| image.starts_with(t)))
+        .unwrap_or(false);
+    // Let podman check for a newer image as part of creating the container
+    // if it can, rather than pulling separately.
+    let native_pull_newer =
+        image.is_some() && !is_archive && pull == "newer" && podman::version_at_least(3, 0)?;
+    if image.is_some() && pull == "newer" && !native_pull_newer {
+        eprintln!("warning: podman is too old for --pull=newer; using --pull=missing");
+    }
+    let pull_timeout = opts.pull_timeout.map(Duration::from_secs);
+    let image = match image {
+        Some(image) if is_archive => Some(load_archive(&image)?),
+        Some(image) => {
+            if !native_pull_newer {
+                if nix::unistd::isatty(2).unwrap_or(false) {
+                    let mut printer = pull_progress_printer(&image);
+                    ensure_image(&image, &pull, Some(&mut printer), pull_timeout)?;
+                } else {
+                    ensure_image(&image, &pull, None, pull_timeout)?;
+                }
+            }
+            Some(image)
+        }
+        None => None,
+    };
+
+    let workdir = if opts.cwd {
+        let cwd = std::env::current_dir()?;
+        Some(
+            cwd.to_str()
+                .ok_or_else(|| failure::err_msg("non-UTF8 current directory"))?
+                .to_string(),
+        )
+    } else {
+        None
+    };
+
+    let profile_d = match opts.profile_d.as_ref() {
+        Some(p) => {
+            Some(std::fs::read_to_string(p).with_context(|e| format!("Reading {}: {}", p, e))?)
+        }
+        None => None,
+    };
+    let bashrc = match opts.bashrc.as_ref() {
+        Some(p) => {
+            Some(std::fs::read_to_string(p).with_context(|e| format!("Reading {}: {}", p, e))?)
+        }
+        None => None,
+    };
+
+    // If podman will be pulling the image, we don't know its ID yet.
+    let image_id = match image.as_ref() {
+        Some(image) if podman::has_object(podman::InspectType::Image, image)? => {
+            podman::image_id(image)?
+        }
+        _ => String::new(),
+    };
+
+    let config = ContainerConfig {
+        name: name.clone(),
+        image_id,
+        host_resolver: opts.host_resolver,
+        workdir: workdir.clone(),
+        profile_d,
+        bashrc,
+        exit_on_empty: opts.exit_on_empty,
+        no_dbus: opts.no_dbus,
+        read_only: opts.read_only,
+        match_machine_id: opts.match_machine_id,
+        copy_passwd_entry: opts.copy_passwd_entry,
+        stop_signal: opts.stop_signal.clone(),
+        no_media: opts.no_media,
+        custom_entrypoint: opts.entrypoint.is_some(),
+        forward_sockets: opts.forward_sockets.clone(),
+    };
 
     // exec ourself as the entrypoint.  In the future this
     // would be better with podman fd passing.
-    let self_bin = std::fs::read_link("/proc/self/exe")?;
+    let self_bin = get_self_bin()?;
     let self_bin = self_bin
         .as_path()
         .to_str()
         .ok_or_else(|| failure::err_msg("non-UTF8 self"))?;
 
-    let real_uid: u32 = nix::unistd::getuid().into();
-    let privileged = real_uid == 0;
-
     let runtime_dir = get_ensure_runtime_dir()?;
     std::fs::create_dir_all(&runtime_dir)?;
 
+    for p in find_private_tmp(&["/tmp", "/var/tmp"])? {
+        eprintln!(
+            "warning: {} is private to a systemd service (PrivateTmp=yes); the toolbox will share that private directory rather than the host's {}",
+            p, p
+        );
+    }
+
     let mut podman = podman::cmd();
     // The basic arguments.
     podman.args(&[
         "create",
         "--interactive",
         "--tty",
-        "--hostname=toolbox",
         "--network=host",
         // We are not aiming for security isolation here; besides these, the
         // user's home directory is mounted in, so anything that wants to "escape"
         // can just mutate ~/.bashrc for example.
         "--ipc=host",
         "--privileged",
-        "--security-opt=label=disable",
-        "--tmpfs=/run:rw",
     ]);
-    podman.arg(format!("--label={}=true", TOOLBOX_LABEL));
+    if opts.read_only {
+        podman.arg("--read-only");
+    }
+    let hostname = match opts.hostname.as_ref() {
+        Some(h) => h.clone(),
+        None if opts.hostname_matches_host => host_hostname()?,
+        None => DEFAULT_HOSTNAME.to_string(),
+    };
+    podman.arg(format!("--hostname={}", hostname));
+    if !opts.no_tmpfs_run {
+        podman.arg("--tmpfs=/run:rw");
+    }
+    let labeling = opts
+        .security_opts
+        .iter()
+        .any(|o| o.starts_with("label=") && o != "label=disable");
+    if !opts.security_opts.iter().any(|o| o.starts_with("label=")) {
+        podman.arg("--security-opt=label=disable");
+    }
+    for o in opts.security_opts.iter() {
+        podman.arg(format!("--security-opt={}", o));
+    }
+    for g in opts.group_add.iter() {
+        podman.arg(format!("--group-add={}", g));
+    }
+    let mut created_secrets = TransientSecrets::default();
+    create_secrets(&secrets, &mut created_secrets)?;
+    for secret in secrets.iter() {
+        podman.arg(format!("--secret={}", secret.arg));
+    }
+    for t in opts.tmpfs.iter() {
+        podman.arg(format!("--tmpfs={}", t));
+    }
+    for m in opts.mounts.iter() {
+        podman.arg(format!("--mount={}", m));
+    }
+    for h in opts.add_hosts.iter() {
+        podman.arg(format!("--add-host={}", h));
+    }
+    if let Some(n) = opts.pids_limit {
+        podman.arg(format!("--pids-limit={}", n));
+    }
+    if let Some(sig) = opts.stop_signal.as_ref() {
+        podman.arg(format!("--stop-signal={}", sig));
+    }
+    if let Some(m) = opts.memory.as_ref() {
+        podman.arg(format!("--memory={}", m));
+    }
+    if let Some(swap) = opts.memory_swap.as_ref() {
+        podman.arg(format!("--memory-swap={}", swap));
+    }
+    if let Some(cpus) = opts.cpuset_cpus.as_ref() {
+        podman.arg(format!("--cpuset-cpus={}", cpus));
+    }
+    if let Some(mems) = opts.cpuset_mems.as_ref() {
+        podman.arg(format!("--cpuset-mems={}", mems));
+    }
+    if let Some(p) = opts.cgroup_parent.as_ref() {
+        podman.arg(format!("--cgroup-parent={}", p));
+    }
+    podman.arg(format!(
+        "--label={}={}",
+        TOOLBOX_LABEL, LABEL_SCHEMA_VERSION
+    ));
+    if opts.no_init {
+        podman.arg(format!("--label={}=true", NO_INIT_LABEL));
+    }
+    if opts.auto_remove {
+        podman.arg(format!("--label={}=true", AUTO_REMOVE_LABEL));
+    }
+    if opts.pin {
+        let image = image.as_ref().expect("--pin conflicts with --rootfs");
+        let digest = podman::image_digests(image)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| failure::format_err!("Image {} has no digest to pin", image))?;
+        podman.arg(format!("--label={}={}", IMAGE_DIGEST_LABEL, digest));
+    }
     podman.arg(format!("--name={}", name));
+    if native_pull_newer {
+        podman.arg("--pull=newer");
+    }
+    // Podman refuses to overwrite an existing cidfile, and its error is shown as is.
+    if let Some(cidfile) = opts.cidfile.as_ref() {
+        podman.arg(format!("--cidfile={}", cidfile));
+    }
     // In privileged mode we assume we want to control all host processes by default;
     // we're more about debugging/management and less of a "dev container".
-    if privileged {
+    if let Some(pid) = opts.pid.as_ref() {
+        podman.arg(format!("--pid={}", pid));
+    } else if privileged {
         podman.arg("--pid=host");
     }
     // We bind ourself in so we can handle recursive invocation.
     podman.arg(format!("--volume={}:{}:ro", self_bin, USR_BIN_SELF));
 
     // In true privileged mode we don't use userns
-    if !privileged {
+    if let Some(userns) = opts.userns.as_ref() {
+        podman.arg(format!("--userns={}", userns));
+    } else if let Some(id_count) = id_count {
         let uid_plus_one = real_uid + 1;
-        let max_minus_uid = MAX_UID_COUNT - real_uid;
+        let max_minus_uid = id_count - real_uid;
         podman.args(&[
             format!("--uidmap={}:0:1", real_uid),
             format!("--uidmap=0:1:{}", real_uid),
@@ -315,27 +2204,159 @@ fn create(opts: &CreateOpts) -> Fallible<()> {
                 uid_plus_one, uid_plus_one, max_minus_uid
             ),
         ]);
+        // Without an explicit gidmap, podman uses the uidmap for gids too.
+        // In the user namespace, gid 0 is our primary group on the host.
+        if opts.map_group_root {
+            podman.args(&[
+                "--gidmap=0:0:1".to_string(),
+                format!("--gidmap=1:1:{}", id_count),
+            ]);
+        }
     }
 
-    for p in &["/dev", "/usr", "/var", "/etc", "/run", "/tmp"] {
+    for p in HOST_MOUNTS.iter() {
         podman.arg(format!("--volume={}:/host{}:rslave", p, p));
     }
-    if Path::new("/sysroot").exists() {
-        podman.arg("--volume=/sysroot:/host/sysroot:rslave");
+    if let Some(dir) = runtime_dir_volume(&runtime_dir) {
+        podman.arg(format!("--volume={}:/host{}:rslave", dir, dir));
     }
-    if privileged {
+    for p in OPTIONAL_HOST_MOUNTS
+        .iter()
+        .filter(|p| Path::new(p).exists())
+    {
+        podman.arg(format!("--volume={}:/host{}:rslave", p, p));
+    }
+    if privileged && !opts.no_debugfs {
         let debugfs = "/sys/kernel/debug";
         if Path::new(debugfs).exists() {
             // Bind debugfs in privileged mode so we can use e.g. bpftrace
             podman.arg(format!("--volume={}:{}:rslave", debugfs, debugfs));
         }
     }
+    // If SELinux labeling was enabled via --security-opt, the home directory
+    // keeps the host label and is likely inaccessible.  Relabeling it would
+    // recursively change the labels on the host too, so leave that to the user.
+    if labeling && selinux_enforcing() {
+        let home = getenv_required_utf8("HOME")?;
+        eprintln!(
+            "warning: with SELinux labeling enabled, files in {} keep their host label and may be inaccessible in the toolbox; \
+             relabel the directories you need with `chcon -R -t container_file_t DIR`, or add them with `--mount type=bind,source=DIR,target=DIR,relabel=shared`",
+            home
+        );
+    }
+    if let Some(boot) = kernel_debug_boot {
+        podman.arg(format!("--volume={}:/boot:ro,rslave", boot));
+    }
+    if let Some(workdir) = workdir.as_ref() {
+        if !path_is_forwarded(Path::new(workdir)) {
+            podman.arg(format!("--volume={}:{}:rslave", workdir, workdir));
+        }
+    }
     append_preserved_env(&mut podman)?;
+    podman.arg(format!(
+        "--env={}={}",
+        CONFIG_ENV,
+        serde_json::to_string(&config)?
+    ));
+    podman.args(&opts.podman_args);
 
-    podman.arg(&image);
-    podman.args(&[USR_BIN_SELF, "internals", "run-pid1"]);
+    // This also drops the image's CMD
+    if let Some(entrypoint) = opts.entrypoint.as_ref() {
+        podman.arg(format!("--entrypoint={}", entrypoint));
+    }
+    if let Some(rootfs) = rootfs.as_ref() {
+        podman.args(&["--rootfs", rootfs.as_str()]);
+    } else {
+        podman.arg(image.as_ref().expect("image"));
+    }
+    if opts.entrypoint.is_none() {
+        podman.args(&[USR_BIN_SELF, "internals", "run-pid1"]);
+    }
     podman.stdout(Stdio::null());
-    podman.run()?;
+    if let Err(e) = podman.run() {
+        if let Some(problem) = storage_problem {
+            bail!("{}\nThis may be because {}", e, problem);
+        }
+        return Err(e);
+    }
+    if let Some(clone_image) = clone_image {
+        clone_image.keep();
+    }
+    created_secrets.keep();
+    // Only now, so a failed create doesn't lose e.g. the existing
+    // container's saved environment.
+    clear_container_state(&name)?;
+    if record_name {
+        record_generated_name(&name)?;
+    }
+    if opts.name_prefix.is_some() {
+        println!("Created toolbox: {}", name);
+    }
+    if opts.start || opts.verify {
+        podman::start(&name)?;
+    }
+    // Initialize it now, so entering it later is fast
+    if opts.start && !opts.no_init && !opts.verify {
+        initialize(&name, opts.keep_failed).map_err(|e| remove_failed(opts, &name, e))?;
+    }
+    if opts.verify {
+        let r = initialize(&name, opts.keep_failed).and_then(|_| verify(&name));
+        if !opts.start && (r.is_ok() || opts.keep_failed) {
+            podman::cmd()
+                .args(&["stop", name.as_str()])
+                .stdout(Stdio::null())
+                .run()?;
+        }
+        r.with_context(|e| format!("Verifying {}: {}", name, e))
+            .map_err(|e| remove_failed(opts, &name, e.into()))?;
+        println!("Verified toolbox: {}", name);
+    }
+    Ok(())
+}
+
+/// Remove a container whose initialization failed, unless `--keep-failed`.
+fn remove_failed(opts: &CreateOpts, name: &str, e: failure::Error) -> failure::Error {
+    if opts.keep_failed {
+        return e;
+    }
+    let r = podman::cmd()
+        .args(&["rm", "-f", name])
+        .stdout(Stdio::null())
+        .run();
+    match r.and_then(|_| forget_generated_name(name)) {
+        Ok(_) => eprintln!("Removed {} since initialization failed", name),
+        Err(e) => eprintln!("warning: Failed to remove {}: {}", name, e),
+    }
+    e
+}
+
+/// Run the container initialization in a started container, as entering it would.
+/// Unless `keep_failed`, the caller removes the container if this fails.
+fn initialize(name: &str, keep_failed: bool) -> Fallible<()> {
+    let mut opts = podman::ExecOptions {
+        env: vec![state_env()?],
+        ..Default::default()
+    };
+    opts.env.extend(init_jobs_env()?);
+    let mut argv = vec![USR_BIN_SELF, "internals", "exec"];
+    if !keep_failed {
+        argv.push("--removing-failed");
+    }
+    argv.extend(&["--", "true"]);
+    podman::exec_command(name, &argv, &opts).run()
+}
+
+/// Check that initialization of a started container completed.
+fn verify(name: &str) -> Fallible<()> {
+    for stamp in &[
+        entrypoint::CONTAINER_INITIALIZED_STAMP,
+        entrypoint::CONTAINER_INITIALIZED_RUNTIME_STAMP,
+    ] {
+        let present = podman::exec(name, &["test", "-e", stamp], &Default::default())?.success();
+        if !present {
+            bail!("{} is missing after initialization", stamp);
+        }
+    }
     Ok(())
 }
 
@@ -343,18 +2364,49 @@ fn in_container() -> bool {
     Path::new("/run/.containerenv").exists()
 }
 
-fn run(opts: &RunOpts) -> Fallible<()> {
-    if in_container() && !opts.nested {
-        bail!("Already inside a container");
+/// Refuse to create or enter a toolbox from inside a container unless
+/// nesting was requested.
+fn ensure_not_nested(nested: bool) -> Fallible<()> {
+    if nested || !in_container() {
+        return Ok(());
     }
+    match std::env::var(TOOLBOX_NAME_ENV) {
+        Ok(name) => bail!("You're already in toolbox '{}'; use -N to nest", name),
+        Err(_) => bail!("Already inside a container"),
+    }
+}
 
-    let name = opts
-        .name
-        .as_ref()
-        .map(|s| s.as_str())
-        .unwrap_or(DEFAULT_NAME);
+/// The home directory is bind mounted and used as a path in the container,
+/// so it must be absolute.
+fn validate_home(home: &str) -> Fallible<()> {
+    if !home.starts_with('/') {
+        bail!("HOME must be an absolute path, not {:?}", home);
+    }
+    Ok(())
+}
 
-    if !podman::has_object(podman::InspectType::Container, &name)? {
+/// The environment variable passing our `EntrypointState` to `internals exec`.
+fn state_env() -> Fallible<String> {
+    let home = getenv_required_utf8("HOME")?;
+    validate_home(&home)?;
+    let state = EntrypointState {
+        username: getenv_required_utf8("USER")?,
+        uid: nix::unistd::getuid().into(),
+        home,
+    };
+    let state = serde_json::to_string(&state)?;
+    Ok(format!("{}={}", STATE_ENV, state))
+}
+
+fn run(opts: &RunOpts) -> Fallible<()> {
+    ensure_not_nested(opts.nested)?;
+    let info = podman::preflight()?;
+    ensure_local_podman(&info)?;
+
+    let name = container_name(opts.name.as_ref())?;
+    let name = name.as_str();
+
+    if !podman::has_object(podman::InspectType::Container, name)? {
         let toolboxes = get_toolbox_images()?;
         if toolboxes.len() == 0 {
             bail!("No toolbox container or images found; use `create` to create one")
@@ -362,76 +2414,537 @@ fn run(opts: &RunOpts) -> Fallible<()> {
             bail!("No toolbox container '{}' found", name)
         }
     }
+    let container = inspect_container(name)?;
 
-    podman::cmd()
-        .args(&["start", name])
-        .stdout(Stdio::null())
-        .run()?;
+    if container_schema_version(&container)? > LABEL_SCHEMA_VERSION {
+        eprintln!(
+            "warning: {} was created by a newer coretoolbox; some features may not work",
+            name
+        );
+    }
 
-    let mut podman = podman::cmd();
-    podman.args(&["exec", "--interactive", "--tty"]);
-    append_preserved_env(&mut podman)?;
-    let state = EntrypointState {
-        username: getenv_required_utf8("USER")?,
-        uid: nix::unistd::getuid().into(),
-        home: getenv_required_utf8("HOME")?,
+    podman::start(name)?;
+
+    let mut exec_opts = podman::ExecOptions {
+        interactive: true,
+        tty: true,
+        ..Default::default()
     };
-    let state = serde_json::to_string(&state)?;
-    podman.arg(format!("--env={}={}", STATE_ENV, state.as_str()));
-    podman.args(&[name, USR_BIN_SELF, "internals", "exec"]);
-    if opts.as_userns_root {
+    if !opts.no_preserve_env {
+        exec_opts.env.extend(preserved_env()?);
+    }
+    // After the preserved environment, so these take precedence
+    exec_opts.env.extend(read_container_env(name)?);
+    exec_opts.env.extend(opts.env.iter().cloned());
+    if container.label(NO_INIT_LABEL).is_some() {
+        // There's no setup, so podman has to switch users and directories
+        exec_opts.user = opts.user.clone();
+        if opts.preserve_cwd {
+            let cwd = std::env::current_dir()?;
+            match no_init_path(&cwd) {
+                Some(p) => {
+                    let p = p
+                        .to_str()
+                        .ok_or_else(|| failure::err_msg("non-UTF8 current directory"))?;
+                    exec_opts.workdir = Some(p.to_string());
+                }
+                None => eprintln!(
+                    "warning: {} is not visible in the toolbox; recreate it with --cwd or --volume to use it",
+                    cwd.display()
+                ),
+            }
+        }
+        let argv = [
+            "/bin/sh",
+            "-c",
+            "command -v bash >/dev/null && exec bash || exec sh",
+        ];
+        return Err(podman::exec_command(name, &argv, &exec_opts).exec().into());
+    }
+    exec_opts.env.push(state_env()?);
+    exec_opts.env.extend(init_jobs_env()?);
+    let mut podman = podman::exec_command(name, &[USR_BIN_SELF, "internals", "exec"], &exec_opts);
+    // Labels from the image are inherited by the container.
+    let default_user = container.label(DEFAULT_USER_LABEL);
+    let as_userns_root = opts.as_userns_root
+        || (!opts.as_user && opts.user.is_none() && default_user == Some("root"));
+    if as_userns_root {
         podman.arg("--as-userns-root");
     }
+    if let Some(user) = opts.user.as_ref() {
+        podman.arg(format!("--user={}", user));
+    }
+    podman.arg(format!("--entrypoint-timeout={}", opts.entrypoint_timeout));
+    if opts.quiet {
+        podman.arg("--quiet");
+    }
+    if opts.preserve_cwd {
+        let cwd = std::env::current_dir()?;
+        let home = getenv_required_utf8("HOME")?;
+        // Mounts can't be added to an existing container
+        if path_is_forwarded(&cwd) || cwd.starts_with(&home) {
+            let cwd = cwd
+                .to_str()
+                .ok_or_else(|| failure::err_msg("non-UTF8 current directory"))?;
+            podman.arg(format!("--cwd={}", cwd));
+        } else {
+            eprintln!(
+                "warning: {} is not visible in the toolbox; recreate it with --cwd or --volume to use it",
+                cwd.display()
+            );
+        }
+    }
     return Err(podman.exec().into());
 }
 
+/// Inspect a single container, e.g. to read all its labels at once.
+fn inspect_container(name: &str) -> Fallible<podman::ContainerInspect> {
+    podman::container_inspect(&[name.to_string()])?
+        .pop()
+        .ok_or_else(|| failure::format_err!("No toolbox container '{}' found", name))
+}
+
+/// The `LABEL_SCHEMA_VERSION` a container was created with.
+fn container_schema_version(container: &podman::ContainerInspect) -> Fallible<u32> {
+    Ok(match container.label(TOOLBOX_LABEL) {
+        Some("true") => 0,
+        Some(v) => v
+            .parse()
+            .map_err(|_| failure::format_err!("Invalid {} label: {}", TOOLBOX_LABEL, v))?,
+        None => 0,
+    })
+}
+
+/// Returns true if prompting was disabled via `NONINTERACTIVE_ENV`.
+fn interactive_disabled() -> bool {
+    std::env::var_os(NONINTERACTIVE_ENV)
+        .map(|v| !v.is_empty())
+        .unwrap_or(false)
+}
+
+/// Ask the user a yes/no question, defaulting to no.
+fn confirm(prompt: &str) -> Fallible<bool> {
+    if interactive_disabled() {
+        bail!("{} (interactive input disabled; use --force)", prompt);
+    }
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    Ok(matches!(input.trim(), "y" | "Y" | "yes"))
+}
+
+fn rm_all(opts: &RmOpts) -> Fallible<()> {
+    let names: Vec<String> = get_toolbox_containers()?
+        .into_iter()
+        .map(|c| c.name)
+        .collect();
+    if names.is_empty() {
+        println!("No toolbox containers found.");
+        return Ok(());
+    }
+    if !(opts.force || opts.yes) {
+        // Unlike a single container, removing all of them needs a confirmation
+        if !nix::unistd::isatty(0).unwrap_or(false) {
+            bail!("Not removing all toolbox containers without a terminal to confirm; use --force");
+        }
+        if !confirm(&format!(
+            "Remove {} toolbox containers ({})?",
+            names.len(),
+            names.join(", ")
+        ))? {
+            return Ok(());
+        }
+    }
+    for name in names.iter() {
+        podman::cmd()
+            .args(&["rm", "-f", name.as_str()])
+            .stdout(Stdio::null())
+            .run()?;
+        forget_generated_name(name)?;
+    }
+    Ok(())
+}
+
 fn rm(opts: &RmOpts) -> Fallible<()> {
-    if !podman::has_object(podman::InspectType::Container, opts.name.as_str())? {
+    if opts.all {
+        return rm_all(opts);
+    }
+    let name = container_name(opts.name.as_ref())?;
+    if !podman::has_object(podman::InspectType::Container, name.as_str())? {
         return Ok(());
     }
-    let mut podman = podman::cmd();
-    podman
-        .args(&["rm", "-f", opts.name.as_str()])
-        .stdout(Stdio::null());
-    Err(podman.exec().into())
+    // Only prompt interactively, so scripts keep working
+    if !(opts.force || opts.yes)
+        && nix::unistd::isatty(0).unwrap_or(false)
+        && !confirm(&format!("Remove container '{}'?", name))?
+    {
+        return Ok(());
+    }
+    podman::cmd()
+        .args(&["rm", "-f", name.as_str()])
+        .stdout(Stdio::null())
+        .run()?;
+    forget_generated_name(&name)
+}
+
+/// Remove the initialization stamps and initialize again; the steps
+/// are written to be safe to repeat.
+fn repair(opts: &RepairOpts) -> Fallible<()> {
+    ensure_not_nested(false)?;
+    ensure_local_podman(&podman::preflight()?)?;
+    let name = container_name(opts.name.as_ref())?;
+    if !podman::has_object(podman::InspectType::Container, &name)? {
+        bail!("No toolbox container '{}' found", name);
+    }
+    if inspect_container(&name)?.label(NO_INIT_LABEL).is_some() {
+        bail!("Container '{}' was created with --no-init", name);
+    }
+    podman::start(&name)?;
+    let mut stamps = vec![entrypoint::CONTAINER_INITIALIZED_RUNTIME_STAMP];
+    if opts.full {
+        stamps.push(entrypoint::CONTAINER_INITIALIZED_STAMP);
+    }
+    let mut argv = vec!["rm", "-f"];
+    argv.extend(stamps);
+    podman::exec_command(&name, &argv, &Default::default()).run()?;
+    initialize(&name, true)?;
+    if opts.full {
+        println!("Redid the setup and runtime forwards of {}", name);
+    } else {
+        println!("Redid the runtime forwards of {}", name);
+    }
+    Ok(())
+}
+
+/// Remove stopped `--auto-remove` toolboxes created more than `max_age`
+/// seconds ago.
+fn gc_auto(max_age: u64, dry_run: bool) -> Fallible<()> {
+    // Clones reset the label to empty
+    let filter = format!("label={}=true", AUTO_REMOVE_LABEL);
+    let names = podman::container_names(&[&filter, "status=exited", "status=created"])?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    for name in names {
+        let created = podman::container_created(&name)?;
+        if now.saturating_sub(created) < max_age {
+            continue;
+        }
+        if dry_run {
+            println!("Would remove {}", name);
+            continue;
+        }
+        podman::cmd()
+            .args(&["rm", name.as_str()])
+            .stdout(Stdio::null())
+            .run()?;
+        forget_generated_name(&name)?;
+        println!("Removed {}", name);
+    }
+    Ok(())
+}
+
+/// Remove host-side state for containers that no longer exist.
+fn gc(opts: &GcOpts) -> Fallible<()> {
+    if opts.auto {
+        let max_age = match opts.max_age {
+            Some(age) => age,
+            None => {
+                let age = load_config()?.auto_remove_age;
+                let age = age.as_deref().unwrap_or(DEFAULT_AUTO_REMOVE_AGE);
+                parse_age(age).map_err(|e| failure::format_err!("auto_remove_age: {}", e))?
+            }
+        };
+        gc_auto(max_age, opts.dry_run)?;
+    }
+    let statedir = APPDIRS.data_dir().join("containers");
+    let entries = match std::fs::read_dir(&statedir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for e in entries {
+        let e = e?;
+        let name = e.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if podman::has_object(podman::InspectType::Container, name)? {
+            continue;
+        }
+        if opts.dry_run {
+            println!("Would remove state for {}", name);
+            continue;
+        }
+        std::fs::remove_dir_all(e.path())?;
+        println!("Removed state for {}", name);
+    }
+    Ok(())
 }
 
-fn list_toolbox_images() -> Fallible<()> {
+/// Our binary is bind mounted into the container when it starts, so running
+/// containers keep using the old binary after an upgrade until restarted.
+fn update() -> Fallible<()> {
+    let host_digest = sha256_file(get_self_bin()?)?;
+    let filter = format!("label={}", TOOLBOX_LABEL);
+    let names = podman::container_names(&[&filter, "status=running"])?;
+    if names.is_empty() {
+        println!("No running toolbox containers found.");
+        return Ok(());
+    }
+    let mut stale = false;
+    for name in names.iter() {
+        let out = podman::exec_output(name, &["sha256sum", USR_BIN_SELF], &Default::default())?;
+        let digest = String::from_utf8_lossy(&out.stdout);
+        let digest = digest.split_whitespace().next();
+        let status = match digest {
+            _ if !out.status.success() => "unknown (failed to checksum binary)",
+            Some(d) if d == host_digest => "up to date",
+            _ => {
+                stale = true;
+                "stale"
+            }
+        };
+        println!("{}: {}", name, status);
+    }
+    if stale {
+        println!(
+            "Restart stale containers (e.g. `podman restart NAME`) to use the current binary."
+        );
+    }
+    Ok(())
+}
+
+/// Returns all toolbox containers.
+fn get_toolbox_containers() -> Fallible<Vec<podman::ContainerInspect>> {
+    let mut names = Vec::new();
+    for label in &[TOOLBOX_LABEL, D_TOOLBOX_LABEL] {
+        for name in podman::container_names(&[&format!("label={}", label)])? {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    podman::container_inspect(&names)
+}
+
+/// Expand `{{.Field}}` references in a `list --format` template.
+fn render_template(template: &str, fields: &[(&str, String)]) -> Fallible<String> {
+    let mut ret = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        ret.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| failure::format_err!("Unterminated {{{{ in template"))?;
+        let key = rest[start + 2..start + end].trim();
+        let key = key.trim_start_matches('.');
+        let value = fields
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+            .ok_or_else(|| failure::format_err!("Unknown template field: {}", key))?;
+        ret.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+fn list(opts: &ListOpts) -> Fallible<()> {
+    let containers: Vec<_> = get_toolbox_containers()?
+        .into_iter()
+        .filter(|c| !opts.running_only || c.state.running)
+        .filter(|c| !(opts.stopped_only && c.state.running))
+        .collect();
+    if let Some(template) = opts.format.as_ref() {
+        for c in containers.iter() {
+            let fields = [
+                ("Name", c.name.clone()),
+                ("Image", c.image_name.clone()),
+                (
+                    "Digest",
+                    c.label(IMAGE_DIGEST_LABEL).unwrap_or("").to_string(),
+                ),
+                ("Running", c.state.running.to_string()),
+                ("Created", c.created.clone()),
+            ];
+            println!("{}", render_template(template, &fields)?);
+        }
+    } else if containers.is_empty() {
+        println!("No toolbox containers found.");
+    } else {
+        for c in containers.iter() {
+            let status = if c.state.running {
+                "running"
+            } else {
+                "stopped"
+            };
+            println!("{:<24} {:<8} {}", c.name, status, c.image_name);
+        }
+    }
+    Ok(())
+}
+
+fn list_toolbox_images(opts: &ListToolboxImagesOpts) -> Fallible<()> {
     let toolboxes = get_toolbox_images()?;
     if toolboxes.is_empty() {
         println!("No toolbox images found.")
     } else {
         for i in toolboxes {
-            println!("{}", i.names.unwrap()[0]);
+            match i.names.as_ref() {
+                Some(names) if opts.all_tags && !names.is_empty() => {
+                    for name in names {
+                        println!("{}", name);
+                    }
+                }
+                _ => println!("{}", i.name()),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn list_forwards() -> Fallible<()> {
+    fn print_paths<'a, I: IntoIterator<Item = &'a str>>(title: &str, paths: I) {
+        println!("{}:", title);
+        for p in paths {
+            let status = if Path::new(p).exists() {
+                "present"
+            } else {
+                "absent"
+            };
+            println!("  {:<24} {}", p, status);
         }
     }
+    let host_mounts = HOST_MOUNTS.iter().chain(OPTIONAL_HOST_MOUNTS.iter());
+    print_paths("Mounted under /host", host_mounts.cloned());
+    print_paths("Symlinked to /host", STATIC_HOST_FORWARDS.iter().cloned());
+    print_paths(
+        "Symlinked to /host (privileged only)",
+        JOURNAL_FORWARDS.iter().cloned(),
+    );
+    print_paths(
+        "Bind mounted from /host",
+        entrypoint::DATADIRS.iter().cloned(),
+    );
+    let devices: Vec<String> = FORWARDED_DEVICES
+        .iter()
+        .map(|d| format!("/dev/{}", d))
+        .collect();
+    print_paths(
+        "Devices symlinked to /host (unprivileged only)",
+        devices.iter().map(|d| d.as_str()),
+    );
+    Ok(())
+}
+
+/// Find the full path to an executable in `$PATH`, if it isn't already a path.
+fn find_in_path<P: AsRef<Path>>(bin: P) -> Option<PathBuf> {
+    let bin = bin.as_ref();
+    if bin.components().count() > 1 {
+        return Some(bin.to_path_buf());
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|d| d.join(bin))
+        .find(|p| p.is_file())
+}
+
+#[derive(Serialize, Debug)]
+struct VersionInfo {
+    coretoolbox: String,
+    podman: Option<String>,
+    backend: Option<String>,
+}
+
+fn version(opts: &VersionOpts) -> Fallible<()> {
+    let info = VersionInfo {
+        coretoolbox: env!("CARGO_PKG_VERSION").to_string(),
+        podman: podman::version().ok(),
+        backend: find_in_path(podman::binary()).map(|p| p.to_string_lossy().into_owned()),
+    };
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        let unknown = "(unavailable)";
+        println!("coretoolbox: {}", info.coretoolbox);
+        println!("podman:      {}", info.podman.as_deref().unwrap_or(unknown));
+        println!(
+            "backend:     {}",
+            info.backend.as_deref().unwrap_or(unknown)
+        );
+    }
     Ok(())
 }
 
 mod entrypoint {
     use super::CommandRunExt;
-    use super::{EntrypointState, ExecOpts};
+    use super::{ContainerConfig, EntrypointState, ExecOpts};
     use failure::{bail, Fallible, ResultExt};
     use fs2::FileExt;
     use rayon::prelude::*;
     use std::fs::File;
     use std::io::prelude::*;
     use std::os::unix;
+    use std::os::unix::fs::PermissionsExt;
     use std::os::unix::process::CommandExt;
     use std::path::Path;
-    use std::process::Command;
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
 
     static CONTAINER_INITIALIZED_LOCK: &str = "/run/coreos-toolbox.lock";
     /// This file is created when we've generated a "container image" (overlayfs layer)
     /// that has things like our modifications to /etc/passwd, and the root `/`.
-    static CONTAINER_INITIALIZED_STAMP: &str = "/etc/coreos-toolbox.initialized";
+    pub(crate) static CONTAINER_INITIALIZED_STAMP: &str = "/etc/coreos-toolbox.initialized";
     /// This file is created when we've completed *runtime* state configuration
-    /// changes such as bind mounts.
-    static CONTAINER_INITIALIZED_RUNTIME_STAMP: &str = "/run/coreos-toolbox.initialized";
+    /// changes such as bind mounts.  It records the container start (see
+    /// `container_start_id`), since with `--no-tmpfs-run` it survives a
+    /// restart but the mounts don't.
+    pub(crate) static CONTAINER_INITIALIZED_RUNTIME_STAMP: &str = "/run/coreos-toolbox.initialized";
+    /// Written by pid1 when it starts and removed when it exits, identifying
+    /// the container start.
+    static CONTAINER_START_ID: &str = "/run/coreos-toolbox.start-id";
+    /// Active sessions are tracked by files in /run with this prefix,
+    /// suffixed by the pid of the session.
+    static SESSION_PREFIX: &str = "coreos-toolbox.session.";
+    /// Serializes session registration against pid1 deciding to exit.
+    static SESSIONS_LOCK: &str = "/run/coreos-toolbox.sessions.lock";
+    /// How often pid1 checks for active sessions with `create --exit-on-empty`
+    static SESSION_POLL_SECS: u64 = 5;
+    /// Environment variable limiting the number of threads used for
+    /// initialization; 1 makes it serial, which makes errors easier to follow.
+    pub(crate) static INIT_JOBS_ENV: &str = "TOOLBOX_INIT_JOBS";
+    /// Directories that initialization writes to, made writable with `create --read-only`
+    static WRITABLE_DIRS: &[&str] = &["/etc", "/var"];
+    /// Where the writable copies of `WRITABLE_DIRS` live
+    static WRITABLE_COPIES: &str = "/run/coreos-toolbox.rw";
+    /// Records the container start (see `container_start_id`) the copies were made for
+    static WRITABLE_STAMP: &str = "/run/coreos-toolbox.rw.stamp";
+    /// The rcfile for interactive bash with `create --bashrc`
+    static TOOLBOX_BASHRC: &str = "/etc/coreos-toolbox.bashrc";
+    /// The snippet from `create --profile-d`
+    static PROFILE_D_SNIPPET: &str = "/etc/profile.d/coreos-toolbox.sh";
 
     /// Set of directories we explicitly make bind mounts rather than symlinks to /host.
     /// To ensure that paths are the same inside and out.
-    static DATADIRS: &[&str] = &["/srv", "/mnt", "/home"];
+    pub(crate) static DATADIRS: &[&str] = &["/srv", "/mnt", "/home"];
+
+    /// Filtered copy of the host's nsswitch.conf, see `host_nsswitch`.
+    static CONTAINER_NSSWITCH: &str = "/run/coreos-toolbox.nsswitch.conf";
+    /// NSS modules that are part of glibc itself in newer versions.
+    static BUILTIN_NSS_MODULES: &[&str] = &["files", "dns"];
+    /// Directories searched for NSS modules.
+    static NSS_LIBDIRS: &[&str] = &[
+        "/usr/lib64",
+        "/usr/lib",
+        "/lib64",
+        "/lib",
+        "/usr/lib/x86_64-linux-gnu",
+        "/usr/lib/aarch64-linux-gnu",
+    ];
 
     fn rbind<S: AsRef<Path>, D: AsRef<Path>>(src: S, dest: D) -> Fallible<()> {
         let src = src.as_ref();
@@ -446,43 +2959,223 @@ mod entrypoint {
 
     /// Update /etc/passwd with the same user from the host,
     /// and bind mount the homedir.
-    fn adduser(state: &EntrypointState, with_sudo: bool) -> Fallible<()> {
+    fn adduser(state: &EntrypointState, with_sudo: bool, copy_entry: bool) -> Fallible<()> {
         if state.uid == 0 {
             return Ok(());
         }
         let uidstr = format!("{}", state.uid);
-        let mut cmd = Command::new("useradd");
-        cmd.args(&[
-            "--no-create-home",
-            "--home-dir",
-            &state.home,
-            "--uid",
-            &uidstr,
-        ]);
-        if with_sudo {
-            cmd.args(&["--groups", "wheel"]);
+        let copied = if copy_entry {
+            let copied = copy_passwd_entry(state.uid)
+                .with_context(|e| format!("Copying host passwd entry: {}", e))?;
+            if copied.is_none() {
+                // e.g. the account is from LDAP/SSSD rather than /etc/passwd
+                eprintln!("warning: uid {} not found in host /etc/passwd", state.uid);
+            }
+            copied
+        } else {
+            None
+        };
+        let gid = copied.unwrap_or(state.uid);
+        // The image may already have an account with our uid, either because
+        // it ships one or from a previous init; reconcile it with the host user.
+        if copied.is_some() {
+            // Already done
+        } else if passwd_name_for_uid(state.uid)?.is_some() {
+            let mut cmd = Command::new("usermod");
+            cmd.args(&["--home", &state.home]);
+            if with_sudo {
+                cmd.args(&["--append", "--groups", "wheel"]);
+            }
+            cmd.arg(state.username.as_str());
+            cmd.run()?;
+        } else {
+            let mut cmd = Command::new("useradd");
+            cmd.args(&[
+                "--no-create-home",
+                "--home-dir",
+                &state.home,
+                "--uid",
+                &uidstr,
+            ]);
+            if with_sudo {
+                cmd.args(&["--groups", "wheel"]);
+            }
+            cmd.arg(state.username.as_str());
+            cmd.run()?;
+        }
+
+        // Bind mount the homedir rather than use symlinks
+        // as various software is unhappy if the path isn't canonical.
+        std::fs::create_dir_all(&state.home)?;
+        let uid = nix::unistd::Uid::from_raw(state.uid);
+        let gid = nix::unistd::Gid::from_raw(gid);
+        nix::unistd::chown(state.home.as_str(), Some(uid), Some(gid))?;
+        let host_home = format!("/host{}", state.home);
+        rbind(host_home.as_str(), state.home.as_str())?;
+        Ok(())
+    }
+
+    /// Replace any account with our uid or name in the container's /etc/passwd
+    /// with the host's, and add the host's groups for it that the container lacks.
+    /// Returns the account's primary gid, or `None` if the host doesn't have it.
+    fn copy_passwd_entry(uid: u32) -> Fallible<Option<u32>> {
+        let uidstr = uid.to_string();
+        let mut fields = match host_passwd_entry(uid)? {
+            Some(fields) => fields,
+            None => return Ok(None),
+        };
+        // The host's login shell may not be installed in the image
+        if !Path::new(&fields[6]).exists() {
+            fields[6] = "/bin/bash".to_string();
+        }
+        let entry = fields.join(":");
+        let (name, gidstr) = (fields[0].as_str(), fields[3].as_str());
+        let gid = gidstr.parse::<u32>()?;
+
+        let passwd = std::fs::read_to_string("/etc/passwd")?;
+        let mut lines: Vec<&str> = passwd
+            .lines()
+            .filter(|l| {
+                let mut f = l.split(':');
+                let (n, _, u) = (f.next(), f.next(), f.next());
+                n != Some(name) && u != Some(uidstr.as_str())
+            })
+            .collect();
+        lines.push(entry.as_str());
+        std::fs::write("/etc/passwd", lines.join("\n") + "\n")?;
+
+        // Without a shadow entry, pam_unix fails e.g. su and sudo for the account
+        if fields[1] == "x" {
+            let shadow_path = Path::new("/etc/shadow");
+            let shadow = match std::fs::read_to_string(shadow_path) {
+                Ok(s) => Some(s),
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+            let days = now.as_secs() / (24 * 60 * 60);
+            if let Some(updated) = shadow_with_entry(shadow.as_deref().unwrap_or(""), name, days) {
+                std::fs::write(shadow_path, updated)?;
+                if shadow.is_none() {
+                    std::fs::set_permissions(shadow_path, std::fs::Permissions::from_mode(0))?;
+                }
+            }
+        }
+
+        // The primary group, and supplementary groups listing the account
+        let host_group = std::fs::read_to_string("/host/etc/group")?;
+        let group = std::fs::read_to_string("/etc/group")?;
+        let mut group: Vec<String> = group.lines().map(|l| l.to_string()).collect();
+        for hl in host_group.lines() {
+            let hf: Vec<&str> = hl.split(':').collect();
+            if hf.len() != 4 {
+                continue;
+            }
+            let member = hf[3].split(',').any(|m| m == name);
+            if hf[2] != gidstr && !member {
+                continue;
+            }
+            let existing = group.iter_mut().find(|l| {
+                let f: Vec<&str> = l.split(':').collect();
+                f.len() == 4 && (f[0] == hf[0] || f[2] == hf[2])
+            });
+            match existing {
+                // Keep the container's group, but with the same membership
+                Some(l) => {
+                    let already = l
+                        .rsplit(':')
+                        .next()
+                        .unwrap_or("")
+                        .split(',')
+                        .any(|m| m == name);
+                    if member && !already {
+                        if !l.ends_with(':') {
+                            l.push(',');
+                        }
+                        l.push_str(name);
+                    }
+                }
+                None => group.push(hl.to_string()),
+            }
+        }
+        std::fs::write("/etc/group", group.join("\n") + "\n")?;
+
+        Ok(Some(gid))
+    }
+
+    /// Returns `shadow` with a locked entry for `name` added, last changed
+    /// `days` after the epoch, or `None` if it already has one.
+    fn shadow_with_entry(shadow: &str, name: &str, days: u64) -> Option<String> {
+        if shadow.lines().any(|l| l.split(':').next() == Some(name)) {
+            return None;
+        }
+        let mut shadow = shadow.to_string();
+        if !shadow.is_empty() && !shadow.ends_with('\n') {
+            shadow.push('\n');
+        }
+        shadow.push_str(&format!("{}:!!:{}:0:99999:7:::\n", name, days));
+        Some(shadow)
+    }
+
+    /// Look up the passwd entry with the given uid in the host's /etc/passwd.
+    fn host_passwd_entry(uid: u32) -> Fallible<Option<Vec<String>>> {
+        let uidstr = uid.to_string();
+        let host_passwd = std::fs::read_to_string("/host/etc/passwd")?;
+        let entry = match host_passwd
+            .lines()
+            .find(|l| l.split(':').nth(2) == Some(uidstr.as_str()))
+        {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let fields: Vec<String> = entry.split(':').map(|s| s.to_string()).collect();
+        if fields.len() != 7 {
+            bail!("Invalid passwd entry: {}", entry);
+        }
+        Ok(Some(fields))
+    }
+
+    /// Look up the passwd entry with the given uid in the container.
+    fn passwd_entry_for_uid(uid: u32) -> Fallible<Option<Vec<String>>> {
+        passwd_entry(&format!("{}", uid))
+    }
+
+    /// Look up the passwd entry for a user name or uid in the container.
+    fn passwd_entry(user: &str) -> Fallible<Option<Vec<String>>> {
+        let out = Command::new("getent")
+            .args(&["passwd", user])
+            .stderr(Stdio::null())
+            .output()?;
+        if !out.status.success() {
+            return Ok(None);
         }
-        cmd.arg(state.username.as_str());
-        cmd.run()?;
+        let out = String::from_utf8(out.stdout)?;
+        Ok(Some(
+            out.trim_end().split(':').map(|s| s.to_string()).collect(),
+        ))
+    }
 
-        // Bind mount the homedir rather than use symlinks
-        // as various software is unhappy if the path isn't canonical.
-        std::fs::create_dir_all(&state.home)?;
-        let uid = nix::unistd::Uid::from_raw(state.uid);
-        let gid = nix::unistd::Gid::from_raw(state.uid);
-        nix::unistd::chown(state.home.as_str(), Some(uid), Some(gid))?;
-        let host_home = format!("/host{}", state.home);
-        rbind(host_home.as_str(), state.home.as_str())?;
-        Ok(())
+    /// Look up the name of the account with the given uid in the container.
+    fn passwd_name_for_uid(uid: u32) -> Fallible<Option<String>> {
+        Ok(passwd_entry_for_uid(uid)?.and_then(|e| e.into_iter().next()))
     }
 
     /// Symlink a path e.g. /run/dbus/system_bus_socket to the
     /// /host equivalent, creating any necessary parent directories.
     fn host_symlink<P: AsRef<Path> + std::fmt::Display>(p: P) -> Fallible<()> {
-        let path = p.as_ref();
+        host_symlink_under(Path::new("/"), p)
+    }
+
+    /// Like `host_symlink`, but for the path `p` under `root`; the link
+    /// still points to `/host` + `p`.
+    fn host_symlink_under<P: AsRef<Path> + std::fmt::Display>(root: &Path, p: P) -> Fallible<()> {
+        let path = root.join(p.as_ref().strip_prefix("/")?);
+        let path = path.as_path();
         std::fs::create_dir_all(path.parent().unwrap())?;
-        match std::fs::remove_dir_all(path) {
-            Ok(_) => Ok(()),
+        // The image may have a directory, a regular file or a symlink there
+        match std::fs::symlink_metadata(path) {
+            Ok(ref m) if m.is_dir() => std::fs::remove_dir_all(path),
+            Ok(_) => std::fs::remove_file(path),
             Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
             Err(e) => Err(e),
         }?;
@@ -490,38 +3183,309 @@ mod entrypoint {
         Ok(())
     }
 
-    fn init_container_static() -> Fallible<EntrypointState> {
-        let initstamp = Path::new(CONTAINER_INITIALIZED_STAMP);
+    /// Write a sudoers snippet in `dir` giving `username` passwordless sudo.
+    fn write_sudoers(dir: &Path, username: &str) -> std::io::Result<()> {
+        let f = File::create(dir.join(format!("toolbox-{}", username)))?;
+        let mut perms = f.metadata()?.permissions();
+        perms.set_readonly(true);
+        f.set_permissions(perms)?;
+        let mut f = std::io::BufWriter::new(f);
+        writeln!(&mut f, "{} ALL=(ALL) NOPASSWD: ALL", username)?;
+        f.flush()?;
+        Ok(())
+    }
+
+    /// Returns whether `write_sudoers` enabled sudo.  A toolbox without sudo
+    /// is still useful, so don't fail if the image has an unwritable
+    /// /etc/sudoers.d.
+    fn sudo_enabled(r: std::io::Result<()>) -> Fallible<bool> {
+        match r {
+            Ok(_) => Ok(true),
+            Err(ref e)
+                if e.kind() == std::io::ErrorKind::PermissionDenied
+                    || e.raw_os_error() == Some(nix::errno::Errno::EROFS as i32) =>
+            {
+                eprintln!("warning: Not enabling sudo: {}", e);
+                Ok(false)
+            }
+            Err(e) => bail!("Enabling sudo: {}", e),
+        }
+    }
 
+    /// Acquire the lock serializing container initialization, giving
+    /// up after `timeout` seconds rather than hanging forever.
+    fn lock_init(timeout: u64) -> Fallible<File> {
         let lockf = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .open(CONTAINER_INITIALIZED_LOCK)?;
-        lockf.lock_exclusive()?;
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        let mut waiting = false;
+        loop {
+            match lockf.try_lock_exclusive() {
+                Ok(_) => return Ok(lockf),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+            // Another session is initializing; let the user know why we're blocked.
+            if !waiting {
+                eprintln!("Waiting for concurrent initialization to complete...");
+                waiting = true;
+            }
+            if Instant::now() >= deadline {
+                bail!(
+                    "initialization appears stuck; failed to acquire {} after {} seconds",
+                    CONTAINER_INITIALIZED_LOCK,
+                    timeout
+                );
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn nss_module_exists(name: &str) -> bool {
+        BUILTIN_NSS_MODULES.contains(&name)
+            || NSS_LIBDIRS
+                .iter()
+                .any(|d| Path::new(d).join(format!("libnss_{}.so.2", name)).exists())
+    }
+
+    /// Rewrite the host's nsswitch.conf, dropping modules (e.g. `resolve`
+    /// from systemd-resolved) that aren't installed in the container along with
+    /// their `[STATUS=action]` criteria.
+    fn host_nsswitch(contents: &str) -> String {
+        let mut ret = String::new();
+        for line in contents.lines() {
+            let (db, sources) = match line.find(':') {
+                Some(i) if !line.trim_start().starts_with('#') => line.split_at(i + 1),
+                _ => {
+                    ret.push_str(line);
+                    ret.push('\n');
+                    continue;
+                }
+            };
+            ret.push_str(db);
+            let mut skip = false;
+            let mut in_action = false;
+            for tok in sources.split_whitespace() {
+                if in_action || tok.starts_with('[') {
+                    in_action = !tok.ends_with(']');
+                } else {
+                    skip = !nss_module_exists(tok);
+                }
+                if !skip {
+                    ret.push(' ');
+                    ret.push_str(tok);
+                }
+            }
+            ret.push('\n');
+        }
+        ret
+    }
+
+    /// Shows a spinner on the terminal until dropped.  It only appears
+    /// after a short delay, so fast operations don't flash it.
+    struct Spinner {
+        done: Option<std::sync::mpsc::Sender<()>>,
+        thread: Option<std::thread::JoinHandle<()>>,
+    }
+
+    impl Spinner {
+        fn start(message: &'static str) -> Self {
+            use std::sync::mpsc::RecvTimeoutError;
+            let (tx, rx) = std::sync::mpsc::channel::<()>();
+            let thread = std::thread::spawn(move || {
+                if rx.recv_timeout(Duration::from_millis(500)) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                for frame in ['|', '/', '-', '\\'].iter().cycle() {
+                    eprint!("\r{} {}...", frame, message);
+                    if rx.recv_timeout(Duration::from_millis(100)) != Err(RecvTimeoutError::Timeout)
+                    {
+                        break;
+                    }
+                }
+                // Clear the line
+                eprint!("\r\x1b[K");
+            });
+            Spinner {
+                done: Some(tx),
+                thread: Some(thread),
+            }
+        }
+    }
+
+    impl Drop for Spinner {
+        fn drop(&mut self) {
+            // Disconnecting the channel stops the thread
+            self.done.take();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// The distribution identification from an os-release file.
+    struct OsRelease {
+        /// e.g. `fedora`
+        id: String,
+        /// e.g. `rhel fedora` for CentOS
+        id_like: Vec<String>,
+        /// e.g. `39`; absent for rolling releases
+        version_id: Option<String>,
+    }
+
+    impl OsRelease {
+        /// Parse os-release(5); returns `None` if it's missing or has no ID.
+        fn load<P: AsRef<Path>>(path: P) -> Option<Self> {
+            let contents = std::fs::read_to_string(path).ok()?;
+            let mut id = None;
+            let mut id_like = Vec::new();
+            let mut version_id = None;
+            for line in contents.lines() {
+                let mut parts = line.trim().splitn(2, '=');
+                let key = parts.next().unwrap_or("");
+                let value = parts
+                    .next()
+                    .unwrap_or("")
+                    .trim_matches(|c| c == '"' || c == '\'');
+                match key {
+                    "ID" => id = Some(value.to_string()),
+                    "ID_LIKE" => id_like = value.split_whitespace().map(String::from).collect(),
+                    "VERSION_ID" => version_id = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            Some(Self {
+                id: id?,
+                id_like,
+                version_id,
+            })
+        }
+
+        /// Returns true if the distributions are the same or derived from one another.
+        fn is_related(&self, other: &OsRelease) -> bool {
+            self.id == other.id
+                || self.id_like.contains(&other.id)
+                || other.id_like.contains(&self.id)
+        }
+    }
+
+    impl std::fmt::Display for OsRelease {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self.version_id.as_ref() {
+                Some(v) => write!(f, "{} {}", self.id, v),
+                None => write!(f, "{}", self.id),
+            }
+        }
+    }
+
+    /// Facts about the host relevant to initialization, probed once.
+    struct HostInfo {
+        /// The host is booted via ostree, e.g. Fedora CoreOS or Silverblue
+        ostree_booted: bool,
+        /// SELinux is enabled; note podman exposes the host's selinuxfs
+        selinux: bool,
+        /// The host is running systemd
+        systemd: bool,
+        /// The host's distribution
+        os_release: Option<OsRelease>,
+    }
+
+    impl HostInfo {
+        fn probe() -> Self {
+            Self {
+                ostree_booted: Path::new("/host/run/ostree-booted").exists(),
+                selinux: Path::new("/sys/fs/selinux/status").exists(),
+                systemd: Path::new("/host/run/systemd/system").exists(),
+                os_release: OsRelease::load("/host/etc/os-release"),
+            }
+        }
+    }
 
-        let state: EntrypointState =
+    fn init_container_static(
+        host: &HostInfo,
+        config: &ContainerConfig,
+        timeout: u64,
+    ) -> Fallible<EntrypointState> {
+        let initstamp = Path::new(CONTAINER_INITIALIZED_STAMP);
+
+        let _lockf = lock_init(timeout)?;
+
+        let mut state: EntrypointState =
             serde_json::from_str(super::getenv_required_utf8(super::STATE_ENV)?.as_str())?;
+        // `run` checks this, but don't trust older versions
+        if state.home.is_empty() {
+            state.home = passwd_entry_for_uid(state.uid)?
+                .and_then(|e| e.into_iter().nth(5))
+                .filter(|h| h.starts_with('/'))
+                .unwrap_or_else(|| "/root".to_string());
+        }
+        // If the image has an account with our uid, use its name; when copying
+        // the host's entry, it replaces that account, and $USER may differ
+        // from the name in it.
+        if state.uid != 0 {
+            let name = if config.copy_passwd_entry {
+                host_passwd_entry(state.uid)?.and_then(|e| e.into_iter().next())
+            } else {
+                passwd_name_for_uid(state.uid)?
+            };
+            if let Some(name) = name {
+                state.username = name;
+            }
+        }
 
+        // The stamp records the image it was created for; if the image was
+        // e.g. committed from another toolbox, it may carry a stale stamp.
         if initstamp.exists() {
-            return Ok(state);
+            let stamp_image_id = std::fs::read_to_string(initstamp)?;
+            if config.image_id.is_empty() || config.image_id == stamp_image_id.trim() {
+                return Ok(state);
+            }
+            eprintln!("Initialization stamp is from a different image; reinitializing");
         }
 
-        let ostree_based_host = std::path::Path::new("/host/run/ostree-booted").exists();
+        // Things like package names in documentation may not apply
+        if let (Some(host_os), Some(container_os)) =
+            (host.os_release.as_ref(), OsRelease::load("/etc/os-release"))
+        {
+            if !host_os.is_related(&container_os) {
+                eprintln!(
+                    "Note: this toolbox runs {} on a {} host",
+                    container_os, host_os
+                );
+            }
+        }
 
-        // Convert the container to ostree-style layout
-        if ostree_based_host {
+        // Convert the container to ostree-style layout; with a read-only root
+        // we can't, but the runtime bind mounts under /var still work.
+        if host.ostree_booted && config.read_only {
+            DATADIRS.par_iter().try_for_each(|d| -> Fallible<()> {
+                std::fs::create_dir_all(format!("/var{}", d))?;
+                Ok(())
+            })?;
+        } else if host.ostree_booted {
             DATADIRS.par_iter().try_for_each(|d| -> Fallible<()> {
+                if std::fs::symlink_metadata(d)?.file_type().is_symlink() {
+                    return Ok(());
+                }
                 std::fs::remove_dir(d)?;
                 let vard = format!("var{}", d);
                 unix::fs::symlink(&vard, d)?;
-                std::fs::create_dir(&vard)?;
+                std::fs::create_dir_all(&vard)?;
                 Ok(())
             })?;
         }
 
-        // This is another mount point used by udisks
-        unix::fs::symlink("/host/run/media", "/run/media")?;
+        // This is another mount point used by udisks; hosts without
+        // it would just get a dangling link.
+        if !config.no_media
+            && Path::new("/host/run/media").exists()
+            && std::fs::symlink_metadata("/run/media").is_err()
+        {
+            unix::fs::symlink("/host/run/media", "/run/media")?;
+        }
 
         // Remove anaconda cruft
         std::fs::read_dir("/tmp")?.try_for_each(|e| -> Fallible<()> {
@@ -538,11 +3502,21 @@ mod entrypoint {
         // directories.
         super::STATIC_HOST_FORWARDS
             .par_iter()
-            .try_for_each(host_symlink)
+            .filter(|p| !(config.no_dbus && super::SYSTEM_BUS_FORWARDS.contains(p)))
+            .try_for_each(|p| {
+                // With a read-only root, /tmp can't be replaced by a symlink
+                if config.read_only && *p == "/tmp" {
+                    rbind("/host/tmp", p)
+                } else {
+                    host_symlink(p)
+                }
+            })
             .with_context(|e| format!("Enabling static host forwards: {}", e))?;
 
-        let ostree_based_host = std::path::Path::new("/host/run/ostree-booted").exists();
-        if ostree_based_host {
+        if host.ostree_booted
+            && !config.read_only
+            && std::fs::symlink_metadata("/host/ostree").is_err()
+        {
             unix::fs::symlink("sysroot/ostree", "/host/ostree")?;
         }
 
@@ -563,42 +3537,131 @@ mod entrypoint {
         }
 
         // Allow sudo
-        let mut with_sudo = false;
-        if Path::new("/etc/sudoers.d").exists() {
+        let sudoers_d = Path::new("/etc/sudoers.d");
+        let with_sudo = if sudoers_d.exists() {
+            sudo_enabled(write_sudoers(sudoers_d, &state.username))?
+        } else {
+            false
+        };
+
+        if let Some(profile_d) = config.profile_d.as_ref() {
             || -> Fallible<()> {
-                let f = File::create(format!("/etc/sudoers.d/toolbox-{}", state.username))?;
-                let mut perms = f.metadata()?.permissions();
-                perms.set_readonly(true);
-                f.set_permissions(perms)?;
-                let mut f = std::io::BufWriter::new(f);
-                writeln!(&mut f, "{} ALL=(ALL) NOPASSWD: ALL", state.username)?;
-                f.flush()?;
-                with_sudo = true;
+                std::fs::write(PROFILE_D_SNIPPET, profile_d)?;
+                let perms = std::fs::Permissions::from_mode(0o644);
+                std::fs::set_permissions(PROFILE_D_SNIPPET, perms)?;
                 Ok(())
             }()
-            .with_context(|e| format!("Enabling sudo: {}", e))?;
+            .with_context(|e| format!("Installing {}: {}", PROFILE_D_SNIPPET, e))?;
         }
 
-        adduser(&state, with_sudo)?;
-        let _ = File::create(&initstamp)?;
+        if let Some(bashrc) = config.bashrc.as_ref() {
+            let contents = format!(
+                "# Generated by coretoolbox from create --bashrc\nif [ -f ~/.bashrc ]; then . ~/.bashrc; fi\n{}",
+                bashrc
+            );
+            std::fs::write(TOOLBOX_BASHRC, contents)
+                .with_context(|e| format!("Installing {}: {}", TOOLBOX_BASHRC, e))?;
+        }
+
+        adduser(&state, with_sudo, config.copy_passwd_entry)?;
+        std::fs::write(&initstamp, config.image_id.as_bytes())?;
 
         Ok(state)
     }
 
-    fn init_container_runtime() -> Fallible<()> {
-        let initstamp = Path::new(CONTAINER_INITIALIZED_RUNTIME_STAMP);
-        if initstamp.exists() {
+    /// Identifies the current start of the container, as recorded by
+    /// `run_pid1`.  With `--pid=host` our pid1 isn't pid 1, so that can't
+    /// be used; it's only the fallback for a custom `--entrypoint`, or a
+    /// container started by an older version.
+    fn container_start_id(config: &ContainerConfig) -> Fallible<String> {
+        let pid1_id = pid1_start_time()?;
+        if config.custom_entrypoint {
+            return Ok(pid1_id);
+        }
+        // `podman start` may return before pid1 has written it
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match std::fs::read_to_string(CONTAINER_START_ID) {
+                Ok(id) => return Ok(id.trim().to_string()),
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            // Initialized by an older version in this start
+            if stamp_is_current(CONTAINER_INITIALIZED_RUNTIME_STAMP, &pid1_id)?
+                || Instant::now() >= deadline
+            {
+                return Ok(pid1_id);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// The start time (in clock ticks since boot) of pid 1.
+    fn pid1_start_time() -> Fallible<String> {
+        let stat = std::fs::read_to_string("/proc/1/stat")?;
+        // The command name may contain spaces; fields resume after its ')'.
+        // starttime is field 22, i.e. the 20th after the command name.
+        stat.rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(19))
+            .map(|s| s.to_string())
+            .ok_or_else(|| failure::format_err!("Failed to parse /proc/1/stat"))
+    }
+
+    /// Returns true if `stamp` was written during the current container start.
+    fn stamp_is_current(stamp: &str, start_id: &str) -> Fallible<bool> {
+        let stamp = Path::new(stamp);
+        if !stamp.exists() {
+            return Ok(false);
+        }
+        Ok(std::fs::read_to_string(stamp)?.trim() == start_id)
+    }
+
+    /// With a read-only root filesystem, replace the directories initialization
+    /// writes to with copies on the /run tmpfs.  Since those don't persist, this
+    /// and the static initialization happen again on each start.
+    fn make_writable(config: &ContainerConfig, timeout: u64) -> Fallible<()> {
+        let start_id = container_start_id(config)?;
+        if stamp_is_current(WRITABLE_STAMP, &start_id)? {
             return Ok(());
         }
 
-        let lockf = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(CONTAINER_INITIALIZED_LOCK)?;
-        lockf.lock_exclusive()?;
+        let _lockf = lock_init(timeout)?;
 
-        if initstamp.exists() {
+        if stamp_is_current(WRITABLE_STAMP, &start_id)? {
+            return Ok(());
+        }
+
+        for d in WRITABLE_DIRS {
+            let copy = format!("{}{}", WRITABLE_COPIES, d);
+            if Path::new(&copy).exists() {
+                std::fs::remove_dir_all(&copy)?;
+            }
+            std::fs::create_dir_all(&copy)?;
+            Command::new("cp")
+                .args(&["-a", "--", &format!("{}/.", d), &copy])
+                .run()?;
+            rbind(&copy, d)?;
+        }
+
+        std::fs::write(WRITABLE_STAMP, start_id.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn init_container_runtime(
+        host: &HostInfo,
+        state: &EntrypointState,
+        config: &ContainerConfig,
+        timeout: u64,
+    ) -> Fallible<()> {
+        let start_id = container_start_id(config)?;
+        if stamp_is_current(CONTAINER_INITIALIZED_RUNTIME_STAMP, &start_id)? {
+            return Ok(());
+        }
+
+        let _lockf = lock_init(timeout)?;
+
+        if stamp_is_current(CONTAINER_INITIALIZED_RUNTIME_STAMP, &start_id)? {
             return Ok(());
         }
 
@@ -606,7 +3669,13 @@ mod entrypoint {
         {
             let runtime_dir = super::get_ensure_runtime_dir()?;
             let runtime_dir_p = std::path::Path::new(&runtime_dir);
-            if !runtime_dir_p.exists() {
+            let host_runtime_dir = format!("/host{}", runtime_dir);
+            if !Path::new(&host_runtime_dir).exists() {
+                eprintln!(
+                    "warning: {} is not available in the container; not forwarding it",
+                    runtime_dir
+                );
+            } else if !runtime_dir_p.exists() {
                 std::fs::create_dir_all(runtime_dir_p.parent().expect("runtime dir parent"))?;
                 host_symlink(runtime_dir)
                     .with_context(|e| format!("Forwarding runtime dir: {}", e))?;
@@ -618,7 +3687,7 @@ mod entrypoint {
         // think it can do domain transitions to rpm_exec_t, which
         // isn't actually permitted.
         let sysfs_selinux = "/sys/fs/selinux";
-        if Path::new(sysfs_selinux).join("status").exists() {
+        if host.selinux {
             let empty_path = Path::new("/usr/share/empty");
             let empty_path = if empty_path.exists() {
                 empty_path
@@ -630,12 +3699,77 @@ mod entrypoint {
             rbind(empty_path, sysfs_selinux)?;
         }
 
-        let ostree_based_host = std::path::Path::new("/host/run/ostree-booted").exists();
+        // Reading the system journal requires privileges on the host
+        if state.uid == 0 && host.systemd {
+            super::JOURNAL_FORWARDS
+                .par_iter()
+                .filter(|p| Path::new(&format!("/host{}", p)).exists())
+                .try_for_each(host_symlink)
+                .with_context(|e| format!("Forwarding journal: {}", e))?;
+        }
+
+        // Most sockets live in /run, which is a fresh tmpfs on each start
+        for p in config.forward_sockets.iter() {
+            // e.g. in /tmp; replacing it would remove the host's socket
+            if super::path_is_forwarded(Path::new(p)) {
+                continue;
+            }
+            if !Path::new(&format!("/host{}", p)).exists() {
+                eprintln!("warning: {} doesn't exist on the host (yet)", p);
+            }
+            host_symlink(p).with_context(|e| format!("Forwarding {}: {}", p, e))?;
+        }
+
+        // This is a mount rather than a copy so it tracks the host; being a mount,
+        // it has to be redone on each start, hence here rather than static init.
+        if config.match_machine_id {
+            || -> Fallible<()> {
+                let host_machine_id = Path::new("/host/etc/machine-id");
+                if !host_machine_id.exists() {
+                    eprintln!("warning: host has no /etc/machine-id");
+                    return Ok(());
+                }
+                let machine_id = Path::new("/etc/machine-id");
+                if !machine_id.exists() {
+                    std::fs::write(machine_id, "")?;
+                }
+                Command::new("mount")
+                    .args(&["--bind", "-o", "ro"])
+                    .arg(host_machine_id)
+                    .arg(machine_id)
+                    .run()?;
+                Ok(())
+            }()
+            .with_context(|e| format!("Forwarding machine-id: {}", e))?;
+        }
+
+        // Resolve names the same way the host does; we share its network namespace.
+        if config.host_resolver {
+            || -> Fallible<()> {
+                let host_resolv = Path::new("/host/etc/resolv.conf");
+                if host_resolv.exists() {
+                    rbind(host_resolv, "/etc/resolv.conf")?;
+                }
+                let host_nss = Path::new("/host/etc/nsswitch.conf");
+                if host_nss.exists() {
+                    let contents = std::fs::read_to_string(host_nss)?;
+                    std::fs::write(CONTAINER_NSSWITCH, host_nsswitch(&contents))?;
+                    // e.g. musl-based images don't have one
+                    let nsswitch = Path::new("/etc/nsswitch.conf");
+                    if !nsswitch.exists() {
+                        std::fs::write(nsswitch, "")?;
+                    }
+                    rbind(CONTAINER_NSSWITCH, nsswitch)?;
+                }
+                Ok(())
+            }()
+            .with_context(|e| format!("Forwarding host resolver: {}", e))?;
+        }
 
         // Propagate standard mount points into the container.
         // We make these bind mounts instead of symlinks as
         // some programs get confused by absolute paths.
-        if ostree_based_host {
+        if host.ostree_booted {
             DATADIRS.par_iter().try_for_each(|d| -> Fallible<()> {
                 let vard = format!("var{}", d);
                 let hostd = format!("/host/{}", &vard);
@@ -650,71 +3784,399 @@ mod entrypoint {
             })?;
         }
 
+        std::fs::write(CONTAINER_INITIALIZED_RUNTIME_STAMP, start_id.as_bytes())?;
+
         Ok(())
     }
 
+    /// A failed initialization leaves the container as is, unless `create
+    /// --keep-failed=false` is about to remove it; tell the user how to investigate.
+    fn init_failed(config: &ContainerConfig, opts: &ExecOpts, e: failure::Error) -> failure::Error {
+        if opts.removing_failed {
+            return e;
+        }
+        let name = if config.name.is_empty() {
+            "NAME"
+        } else {
+            config.name.as_str()
+        };
+        eprintln!(
+            "Initialization failed; the container '{}' has been kept for inspection.
+To retry with serial, easier to follow output, use `TOOLBOX_INIT_JOBS=1 coretoolbox run -n {}`;
+`coretoolbox repair -n {} --full` redoes the setup from scratch, and
+`coretoolbox rm -n {}` removes it.",
+            name, name, name, name
+        );
+        e
+    }
+
     pub(crate) fn exec(opts: ExecOpts) -> Fallible<()> {
         use nix::sys::stat::Mode;
-        if !super::in_container() {
-            bail!("Not inside a container");
+        if std::env::var_os(INIT_JOBS_ENV).is_some() {
+            let jobs = super::getenv_required_utf8(INIT_JOBS_ENV)?
+                .parse::<usize>()
+                .with_context(|e| format!("Parsing {}: {}", INIT_JOBS_ENV, e))?;
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build_global()?;
         }
-        let state = init_container_static()
-            .with_context(|e| format!("Initializing container (static): {}", e))?;
-        init_container_runtime()
-            .with_context(|e| format!("Initializing container (runtime): {}", e))?;
+        let config = ContainerConfig::from_env()?;
+        if config.read_only {
+            make_writable(&config, opts.entrypoint_timeout)
+                .with_context(|e| format!("Making /etc and /var writable: {}", e))?;
+        }
+        let spinner = if !opts.quiet
+            && nix::unistd::isatty(1).unwrap_or(false)
+            && nix::unistd::isatty(2).unwrap_or(false)
+        {
+            Some(Spinner::start("Setting up toolbox"))
+        } else {
+            None
+        };
+        let host = HostInfo::probe();
+        let state = init_container_static(&host, &config, opts.entrypoint_timeout)
+            .with_context(|e| format!("Initializing container (static): {}", e))
+            .map_err(|e| init_failed(&config, &opts, e.into()))?;
+        init_container_runtime(&host, &state, &config, opts.entrypoint_timeout)
+            .with_context(|e| format!("Initializing container (runtime): {}", e))
+            .map_err(|e| init_failed(&config, &opts, e.into()))?;
+        drop(spinner);
         let initstamp = Path::new(CONTAINER_INITIALIZED_STAMP);
         if !initstamp.exists() {
             bail!("toolbox not initialized");
         }
+        // Validate before registering the session
+        let target_user = match opts.user.as_ref() {
+            Some(user) => match passwd_entry(user)? {
+                Some(entry) if entry.len() >= 6 => Some(entry),
+                _ => bail!("No user {} in the container", user),
+            },
+            None => None,
+        };
+        if config.exit_on_empty {
+            register_session()?;
+        }
         // Set a sane umask (022) by default; something seems to be setting it to 077
         nix::sys::stat::umask(Mode::S_IWGRP | Mode::S_IWOTH);
-        let mut cmd = if opts.as_userns_root || !Path::new("/etc/sudoers.d").exists() {
-            Command::new("/bin/bash")
+        // Run a command via the shell so it gets the same environment
+        let shell_exec = ["-c", "exec \"$@\""];
+        let rcfile = config.bashrc.is_some() && opts.command.is_empty();
+        let mut cmd = if target_user.is_none()
+            && (opts.as_userns_root || !Path::new("/etc/sudoers.d").exists())
+        {
+            let mut cmd = Command::new("/bin/bash");
+            if !opts.command.is_empty() {
+                cmd.args(&shell_exec);
+            }
+            cmd
         } else {
             let mut cmd = Command::new("setpriv");
-            cmd.args(&[
-                "--inh-caps=-all",
-                "su",
-                "--preserve-environment",
-                state.username.as_str(),
-            ])
-            .env("HOME", state.home.as_str());
+            cmd.args(&["--inh-caps=-all", "su", "--preserve-environment"]);
+            if !opts.command.is_empty() {
+                cmd.args(&shell_exec).arg("--");
+            } else if rcfile {
+                // The rcfile is for bash, whatever the user's shell
+                cmd.args(&["--shell=/bin/bash", "--"]);
+            }
+            match target_user.as_ref() {
+                Some(entry) => cmd.arg(entry[0].as_str()).env("HOME", entry[5].as_str()),
+                None => cmd
+                    .arg(state.username.as_str())
+                    .env("HOME", state.home.as_str()),
+            };
             cmd
         };
+        if rcfile {
+            cmd.args(&["--rcfile", TOOLBOX_BASHRC]);
+        }
+        if !opts.command.is_empty() {
+            // $0 for the shell
+            cmd.arg("coretoolbox").args(&opts.command);
+        }
+        if let Some(workdir) = opts.cwd.as_ref().or(config.workdir.as_ref()) {
+            if Path::new(workdir).exists() {
+                cmd.current_dir(workdir);
+            }
+        }
+        if !config.name.is_empty() {
+            cmd.env(super::TOOLBOX_NAME_ENV, config.name.as_str());
+        }
+
+        Err(cmd
+            .env_remove(super::STATE_ENV)
+            .env_remove(super::CONFIG_ENV)
+            .exec()
+            .into())
+    }
+
+    fn lock_sessions() -> Fallible<File> {
+        let lockf = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(SESSIONS_LOCK)?;
+        lockf.lock_exclusive()?;
+        Ok(lockf)
+    }
+
+    /// Record this process as an active session; since we exec the shell,
+    /// the session lives as long as our pid does.
+    fn register_session() -> Fallible<()> {
+        let _lockf = lock_sessions()?;
+        let path = Path::new("/run").join(format!("{}{}", SESSION_PREFIX, std::process::id()));
+        let _ = File::create(path)?;
+        Ok(())
+    }
 
-        Err(cmd.env_remove(super::STATE_ENV).exec().into())
+    /// Count the active sessions, pruning those whose process has exited.
+    fn count_sessions() -> Fallible<usize> {
+        let mut count = 0;
+        for e in std::fs::read_dir("/run")? {
+            let e = e?;
+            let name = e.file_name();
+            let pid = match name.to_str().and_then(|n| n.strip_prefix(SESSION_PREFIX)) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if Path::new("/proc").join(pid).exists() {
+                count += 1;
+            } else {
+                std::fs::remove_file(e.path())?;
+            }
+        }
+        Ok(count)
     }
 
     pub(crate) fn run_pid1() -> Fallible<()> {
-        unsafe {
-            signal_hook::register(signal_hook::SIGCHLD, waitpid_all)?;
-            signal_hook::register(signal_hook::SIGTERM, || std::process::exit(0))?;
+        // Signals are delivered via a self-pipe, so we do the actual work
+        // here rather than in a signal handler.
+        let config = ContainerConfig::from_env()?;
+        let stop_signal = match config.stop_signal.as_ref() {
+            Some(sig) => sig
+                .parse::<nix::sys::signal::Signal>()
+                .map_err(|_| failure::format_err!("Invalid stop signal {}", sig))?
+                as i32,
+            None => signal_hook::SIGTERM,
         };
+        let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGCHLD, stop_signal])?;
+        // Unique across starts, e.g. of a container with `--no-tmpfs-run`
+        // where the previous one survives if we didn't exit cleanly.
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let start_id = format!("{}.{}", now.as_nanos(), std::process::id());
+        let tmp = format!("{}.tmp", CONTAINER_START_ID);
+        std::fs::write(&tmp, start_id.as_bytes())?;
+        std::fs::rename(&tmp, CONTAINER_START_ID)?;
+        if config.exit_on_empty {
+            std::thread::spawn(|| {
+                if let Err(e) = exit_when_idle() {
+                    eprintln!("Tracking sessions: {}", e);
+                }
+            });
+        }
+        pid1_loop(&signals);
+        exit_pid1()
+    }
+
+    /// Reap children as they exit, until a signal other than SIGCHLD
+    /// arrives, i.e. the stop signal.
+    fn pid1_loop(signals: &signal_hook::iterator::Signals) {
+        for signal in signals.forever() {
+            if signal != signal_hook::SIGCHLD {
+                return;
+            }
+            waitpid_all();
+        }
+    }
+
+    /// Remove the start marker so a later start can't be mistaken for this one.
+    fn exit_pid1() -> ! {
+        let _ = std::fs::remove_file(CONTAINER_START_ID);
+        std::process::exit(0)
+    }
+
+    /// Exit once we've seen a session and then none for two consecutive
+    /// polls; the grace period avoids racing with a session that's starting up.
+    fn exit_when_idle() -> Fallible<()> {
+        let mut seen_session = false;
+        let mut idle_polls = 0;
         loop {
-            std::thread::sleep(std::time::Duration::from_secs(1_000_000));
+            std::thread::sleep(Duration::from_secs(SESSION_POLL_SECS));
+            let _lockf = lock_sessions()?;
+            if count_sessions()? > 0 {
+                seen_session = true;
+                idle_polls = 0;
+            } else if seen_session {
+                idle_polls += 1;
+                if idle_polls >= 2 {
+                    exit_pid1();
+                }
+            }
         }
     }
 
+    /// Reap all exited children.  Multiple exits may be coalesced
+    /// into one SIGCHLD, so loop until there are none left.
     fn waitpid_all() {
+        use nix::errno::Errno;
         use nix::sys::wait::WaitStatus;
         loop {
             match nix::sys::wait::waitpid(None, Some(nix::sys::wait::WaitPidFlag::WNOHANG)) {
-                Ok(status) => match status {
-                    WaitStatus::StillAlive => break,
-                    _ => {}
-                },
-                Err(_) => break,
+                Ok(WaitStatus::StillAlive) => break,
+                Ok(_) => {}
+                Err(nix::Error::Sys(Errno::EINTR)) => {}
+                // No children left
+                Err(nix::Error::Sys(Errno::ECHILD)) => break,
+                Err(e) => {
+                    eprintln!("waitpid: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_shadow_with_entry() {
+            let shadow = "root:!locked::0:99999:7:::\nbin:*:18000:0:99999:7:::";
+            assert_eq!(
+                shadow_with_entry(shadow, "user", 19000).unwrap(),
+                "root:!locked::0:99999:7:::\nbin:*:18000:0:99999:7:::\nuser:!!:19000:0:99999:7:::\n"
+            );
+            assert_eq!(
+                shadow_with_entry("", "user", 19000).unwrap(),
+                "user:!!:19000:0:99999:7:::\n"
+            );
+            assert_eq!(shadow_with_entry(shadow, "bin", 19000), None);
+        }
+
+        #[test]
+        fn test_host_symlink_replaces() {
+            let root = tempfile::tempdir().unwrap();
+            let root = root.path();
+            std::fs::create_dir_all(root.join("run/foo")).unwrap();
+            std::fs::write(root.join("run/foo/file"), "x").unwrap();
+            std::fs::create_dir_all(root.join("run/dir/sub")).unwrap();
+            for p in &["/run/foo/file", "/run/dir", "/run/new/sock"] {
+                host_symlink_under(root, p).unwrap();
+                let link = root.join(&p[1..]);
+                assert_eq!(
+                    std::fs::read_link(&link).unwrap(),
+                    Path::new(&format!("/host{}", p))
+                );
+            }
+            // Repeating it replaces the existing link
+            host_symlink_under(root, "/run/foo/file").unwrap();
+        }
+
+        #[test]
+        fn test_write_sudoers() {
+            let dir = tempfile::tempdir().unwrap();
+            assert!(sudo_enabled(write_sudoers(dir.path(), "user")).unwrap());
+            let path = dir.path().join("toolbox-user");
+            assert_eq!(
+                std::fs::read_to_string(&path).unwrap(),
+                "user ALL=(ALL) NOPASSWD: ALL\n"
+            );
+            assert!(std::fs::metadata(&path).unwrap().permissions().readonly());
+        }
+
+        /// Set when `test_pid1_reaps` runs itself in a subprocess
+        const REAP_TEST_ENV: &str = "CORETOOLBOX_TEST_REAP";
+
+        /// Returns true if any thread of this process has children,
+        /// including exited ones that haven't been reaped.
+        fn has_children() -> bool {
+            std::fs::read_dir("/proc/self/task").unwrap().any(|t| {
+                let children = t.unwrap().path().join("children");
+                !std::fs::read_to_string(children).unwrap().trim().is_empty()
+            })
+        }
+
+        /// `pid1_loop` reaps any child of the process, so run it in a
+        /// subprocess with only this test, rather than alongside others.
+        #[test]
+        fn test_pid1_reaps() {
+            use nix::errno::Errno;
+            use nix::sys::wait::{waitpid, WaitPidFlag};
+            if std::env::var_os(REAP_TEST_ENV).is_none() {
+                let status = Command::new(std::env::current_exe().unwrap())
+                    .args(&["--exact", "entrypoint::tests::test_pid1_reaps"])
+                    .env(REAP_TEST_ENV, "1")
+                    .stdout(Stdio::null())
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+                return;
+            }
+            let signals =
+                signal_hook::iterator::Signals::new(&[signal_hook::SIGCHLD, signal_hook::SIGUSR1])
+                    .unwrap();
+            for _ in 0..200 {
+                Command::new("true").spawn().unwrap();
+            }
+            // Stop once everything was reaped, or give up
+            std::thread::spawn(|| {
+                let deadline = Instant::now() + Duration::from_secs(30);
+                while has_children() && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                nix::sys::signal::raise(nix::sys::signal::Signal::SIGUSR1).unwrap();
+            });
+            pid1_loop(&signals);
+            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Err(nix::Error::Sys(Errno::ECHILD)) => {}
+                r => panic!("children left: {:?}", r),
             }
         }
+
+        #[test]
+        fn test_sudoers_unwritable() {
+            use nix::errno::Errno;
+            let err = |e: Errno| Err(std::io::Error::from_raw_os_error(e as i32));
+            assert!(!sudo_enabled(err(Errno::EROFS)).unwrap());
+            assert!(!sudo_enabled(err(Errno::EACCES)).unwrap());
+            assert!(!sudo_enabled(err(Errno::EPERM)).unwrap());
+            assert!(sudo_enabled(err(Errno::ENOSPC)).is_err());
+        }
+    }
+}
+
+/// An error as printed with `--error-format=json`
+#[derive(Serialize, Debug)]
+struct ErrorInfo {
+    message: String,
+    causes: Vec<String>,
+    /// `io` if caused by an I/O error, otherwise `other`
+    kind: &'static str,
+}
+
+impl ErrorInfo {
+    fn new(e: &failure::Error) -> Self {
+        let is_io = e
+            .iter_chain()
+            .any(|c| c.downcast_ref::<std::io::Error>().is_some());
+        ErrorInfo {
+            message: e.to_string(),
+            causes: e.iter_causes().map(|c| c.to_string()).collect(),
+            kind: if is_io { "io" } else { "other" },
+        }
     }
 }
 
 /// Primary entrypoint
 fn main() {
+    let mut json_errors = false;
     || -> Fallible<()> {
         let mut args: Vec<String> = std::env::args().collect();
         if let Some("internals") = args.get(1).map(|s| s.as_str()) {
             args.remove(1);
+            // These are only run by us, as the container entrypoint or via `podman exec`
+            if !in_container() {
+                bail!("internal command, do not invoke directly");
+            }
             let opts = InternalOpt::from_iter(args.iter());
             match opts {
                 InternalOpt::Exec(execopts) => entrypoint::exec(execopts),
@@ -722,16 +4184,105 @@ fn main() {
             }
         } else {
             let opts = Opt::from_iter(args.iter());
-            match opts {
-                Opt::Create(ref opts) => create(opts),
-                Opt::Run(ref opts) => run(opts),
-                Opt::Rm(ref opts) => rm(opts),
-                Opt::ListToolboxImages => list_toolbox_images(),
+            json_errors = opts.error_format == "json";
+            if opts.sudo && !nix::unistd::getuid().is_root() {
+                return reexec_sudo(&args);
+            }
+            cmdrunext::set_redacted_env(&opts.redact_env);
+            if let Some(level) = opts.podman_log_level.as_ref() {
+                podman::set_log_level(level);
+            }
+            match opts.cmd {
+                Cmd::Create(ref opts) => create(opts),
+                Cmd::Run(ref opts) => run(opts),
+                Cmd::Rm(ref opts) => rm(opts),
+                Cmd::List(ref opts) => list(opts),
+                Cmd::Env(ref cmd) => env(cmd),
+                Cmd::Gc(ref opts) => gc(opts),
+                Cmd::Repair(ref opts) => repair(opts),
+                Cmd::ListToolboxImages(ref opts) => list_toolbox_images(opts),
+                Cmd::ListForwards => list_forwards(),
+                Cmd::Update => update(),
+                Cmd::Version(ref opts) => version(opts),
             }
         }
     }()
     .unwrap_or_else(|e| {
-        eprintln!("error: {}", e);
+        let json = if json_errors {
+            serde_json::to_string(&ErrorInfo::new(&e)).ok()
+        } else {
+            None
+        };
+        match json {
+            Some(json) => eprintln!("{}", json),
+            None => eprintln!("error: {}", e),
+        }
         std::process::exit(1)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_name() {
+        let generated = || Some("gen-1".to_string());
+        std::env::remove_var(NAME_ENV);
+        assert_eq!(choose_container_name(None, None).unwrap(), DEFAULT_NAME);
+        assert_eq!(choose_container_name(None, generated()).unwrap(), "gen-1");
+        std::env::set_var(NAME_ENV, "fromenv");
+        assert_eq!(choose_container_name(None, generated()).unwrap(), "fromenv");
+        let name = "fromopt".to_string();
+        assert_eq!(
+            choose_container_name(Some(&name), generated()).unwrap(),
+            "fromopt"
+        );
+        std::env::set_var(NAME_ENV, "bad/name");
+        assert!(choose_container_name(None, None).is_err());
+        std::env::remove_var(NAME_ENV);
+    }
+
+    #[test]
+    fn test_validate_home() {
+        assert!(validate_home("/home/user").is_ok());
+        assert!(validate_home("").is_err());
+        assert!(validate_home("home/user").is_err());
+        assert!(validate_home("~").is_err());
+    }
+
+    #[test]
+    fn test_runtime_dir_volume() {
+        assert_eq!(runtime_dir_volume("/run/user/1000"), None);
+        assert_eq!(runtime_dir_volume("/tmp/runtime-user"), None);
+        assert_eq!(
+            runtime_dir_volume("/srv/runtime/user"),
+            Some("/srv/runtime")
+        );
+        assert_eq!(runtime_dir_volume("/runtime-user"), Some("/runtime-user"));
+    }
+
+    #[test]
+    fn test_no_init_path() {
+        assert_eq!(
+            no_init_path(Path::new("/var/home/user/src")),
+            Some(PathBuf::from("/host/var/home/user/src"))
+        );
+        assert_eq!(
+            no_init_path(Path::new("/tmp")),
+            Some(PathBuf::from("/host/tmp"))
+        );
+        assert_eq!(no_init_path(Path::new("/home/user")), None);
+        assert_eq!(no_init_path(Path::new("/variable")), None);
+    }
+
+    #[test]
+    fn test_parse_env_arg() {
+        assert_eq!(parse_env_arg("FOO=bar baz").unwrap(), "FOO=bar baz");
+        assert_eq!(parse_env_arg("FOO=").unwrap(), "FOO=");
+        assert!(parse_env_arg("FOO").is_err());
+        assert!(parse_env_arg("=bar").is_err());
+        assert!(parse_env_arg("F OO=bar").is_err());
+        assert!(parse_env_arg("FOO=bar\nBAZ=1").is_err());
+    }
+}
@@ -1,8 +1,20 @@
-use failure::{bail, Fallible};
+use failure::{bail, Fallible, ResultExt};
+use lazy_static::lazy_static;
 use serde::Deserialize;
 use serde_json;
+use std::ffi::OsString;
 use std::io::prelude::*;
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Values accepted by `podman --log-level`.
+pub(crate) static LOG_LEVELS: &[&str] = &["debug", "info", "warn", "error"];
+
+lazy_static! {
+    static ref LOG_LEVEL: Mutex<Option<String>> = Mutex::new(None);
+}
 
 #[allow(dead_code)]
 pub(crate) enum InspectType {
@@ -10,20 +22,212 @@ pub(crate) enum InspectType {
     Image,
 }
 
+/// Progress while pulling an image, parsed from podman's output.  Podman
+/// only prints per-layer progress lines when its stderr isn't a terminal.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub(crate) enum PullProgress<'a> {
+    /// Started copying a layer
+    BlobStarted(&'a str),
+    /// Finished copying a layer, or found it already present
+    BlobDone(&'a str),
+    /// Copying the image configuration, which happens after all layers
+    Config,
+    /// Any other output, including errors
+    Other(&'a str),
+}
+
+impl<'a> PullProgress<'a> {
+    fn parse(line: &'a str) -> Self {
+        let line = line.trim();
+        if line.starts_with("Copying config") {
+            return PullProgress::Config;
+        }
+        match line.strip_prefix("Copying blob ") {
+            Some(rest) => {
+                let mut parts = rest.split_whitespace();
+                let digest = parts.next().unwrap_or("");
+                match parts.next() {
+                    Some(_) => PullProgress::BlobDone(digest),
+                    None => PullProgress::BlobStarted(digest),
+                }
+            }
+            None => PullProgress::Other(line),
+        }
+    }
+}
+
+/// Options for `podman exec`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExecOptions {
+    /// Keep stdin open
+    pub interactive: bool,
+    /// Allocate a pseudo-terminal
+    pub tty: bool,
+    /// Environment variables to set, as `NAME=VALUE`
+    pub env: Vec<String>,
+    /// Working directory for the command
+    pub workdir: Option<String>,
+    /// User to run the command as, instead of the container's default
+    pub user: Option<String>,
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub(crate) struct ImageInspect {
     pub id: String,
     pub names: Option<Vec<String>>,
 }
 
+impl ImageInspect {
+    /// The first name of the image, or its ID if it has none.
+    pub(crate) fn name(&self) -> &str {
+        self.names
+            .as_ref()
+            .and_then(|n| n.first())
+            .map(|n| n.as_str())
+            .unwrap_or(self.id.as_str())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ContainerInspect {
+    pub name: String,
+    pub image_name: String,
+    pub created: String,
+    pub state: ContainerInspectState,
+    pub config: ContainerInspectConfig,
+}
+
+impl ContainerInspect {
+    /// Returns the value of a label, if set.  Labels can't be removed
+    /// from an image, only reset to the empty string, so that counts
+    /// as unset.
+    pub(crate) fn label(&self, label: &str) -> Option<&str> {
+        self.config
+            .labels
+            .as_ref()
+            .and_then(|l| l.get(label))
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ContainerInspectConfig {
+    pub labels: Option<std::collections::HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub(crate) struct ContainerInspectState {
+    pub running: bool,
+}
+
+/// The podman binary to use; may be overridden via the `podman`
+/// environment variable.
+pub(crate) fn binary() -> OsString {
+    std::env::var_os("podman").unwrap_or_else(|| "podman".into())
+}
+
 pub(crate) fn cmd() -> Command {
-    if let Some(podman) = std::env::var_os("podman") {
-        Command::new(podman)
-    } else {
-        Command::new("podman")
+    let mut c = Command::new(binary());
+    if let Some(level) = LOG_LEVEL.lock().unwrap().as_ref() {
+        c.args(&["--log-level", level.as_str()]);
+    }
+    c
+}
+
+/// Set the `--log-level` passed to every podman invocation.
+pub(crate) fn set_log_level(level: &str) {
+    *LOG_LEVEL.lock().unwrap() = Some(level.to_string());
+}
+
+/// Returns the podman version, e.g. `1.6.2`.
+pub(crate) fn version() -> Fallible<String> {
+    let out = cmd().arg("--version").stderr(Stdio::inherit()).output()?;
+    if !out.status.success() {
+        bail!("podman --version failed")
+    }
+    let out = String::from_utf8(out.stdout)?;
+    match out.split_whitespace().last() {
+        Some(v) => Ok(v.to_string()),
+        None => bail!("Failed to parse podman version from {:?}", out),
+    }
+}
+
+/// Returns true if the podman version is at least `major.minor`.
+pub(crate) fn version_at_least(major: u32, minor: u32) -> Fallible<bool> {
+    let version = version()?;
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|p| p.parse::<u32>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(ma)), Some(Ok(mi))) => Ok((ma, mi) >= (major, minor)),
+        _ => bail!("Failed to parse podman version {}", version),
+    }
+}
+
+/// The parts of `podman info` we use.
+#[derive(Clone, Debug)]
+pub(crate) struct Info {
+    /// True if podman is a client for a remote service, such as
+    /// a `podman machine` VM.
+    pub remote: bool,
+    /// The storage driver, e.g. `overlay` or `vfs`.
+    pub storage_driver: String,
+}
+
+impl Info {
+    fn parse(buf: &[u8]) -> Fallible<Self> {
+        let info: serde_json::Value = serde_json::from_slice(buf)?;
+        // Older versions don't have this field, and don't support remote operation.
+        let remote = info["host"]["serviceIsRemote"].as_bool().unwrap_or(false)
+            || std::env::var_os("CONTAINER_HOST").is_some();
+        // podman 1.x used Go's field names
+        let store = &info["store"];
+        let storage_driver = store["graphDriverName"]
+            .as_str()
+            .or_else(|| store["GraphDriverName"].as_str())
+            .unwrap_or("")
+            .to_string();
+        Ok(Self {
+            remote,
+            storage_driver,
+        })
     }
 }
 
+/// Check that podman works at all, since e.g. misconfigured storage makes
+/// every command fail with an error that doesn't point at the cause.
+/// Returns the information from the same `podman info` call.
+pub(crate) fn preflight() -> Fallible<Info> {
+    let out = match cmd().args(&["info", "--format", "json"]).output() {
+        Ok(out) => out,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!(
+                "{} not found; is podman installed?",
+                binary().to_string_lossy()
+            )
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        let reason = match stderr.trim() {
+            "" => format!("podman info {}", out.status),
+            s => s.to_string(),
+        };
+        bail!(
+            "podman is installed but not functional: {}\n\
+             Run `podman info` for details; `podman system migrate` may fix it",
+            reason
+        )
+    }
+    Ok(Info::parse(&out.stdout).context("Parsing podman info")?)
+}
+
 /// Returns true if an image or container is in the podman
 /// storage.
 pub(crate) fn has_object(t: InspectType, name: &str) -> Fallible<bool> {
@@ -39,6 +243,270 @@ pub(crate) fn has_object(t: InspectType, name: &str) -> Fallible<bool> {
         .success())
 }
 
+/// Returns true if a podman secret exists.
+pub(crate) fn has_secret(id: &str) -> Fallible<bool> {
+    Ok(cmd()
+        .args(&["secret", "inspect", id])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?
+        .success())
+}
+
+/// Returns the contents of a podman secret, or `None` if podman
+/// can't show them (before 4.5).
+pub(crate) fn secret_data(id: &str) -> Fallible<Option<Vec<u8>>> {
+    let out = cmd()
+        .args(&[
+            "secret",
+            "inspect",
+            "--showsecret",
+            "--format",
+            "{{.SecretData}}",
+            id,
+        ])
+        .stderr(Stdio::null())
+        .output()?;
+    if !out.status.success() {
+        return Ok(None);
+    }
+    // The template output ends with a newline
+    let mut data = out.stdout;
+    if data.last() == Some(&b'\n') {
+        data.pop();
+    }
+    Ok(Some(data))
+}
+
+/// Returns when a container was created, in seconds since the epoch.
+pub(crate) fn container_created(name: &str) -> Fallible<u64> {
+    let out = cmd()
+        .args(&[
+            "inspect",
+            "--type",
+            "container",
+            "--format",
+            "{{.Created.Unix}}",
+            name,
+        ])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        bail!("podman inspect failed for container {}", name)
+    }
+    let out = String::from_utf8(out.stdout)?;
+    match out.trim().parse() {
+        Ok(t) => Ok(t),
+        Err(_) => bail!("Failed to parse creation time of {} from {:?}", name, out),
+    }
+}
+
+/// Pull an image.  Without a progress callback, podman's output is passed
+/// through as is.  If `timeout` passes, podman is killed.
+pub(crate) fn pull(
+    name: &str,
+    progress: Option<&mut dyn FnMut(PullProgress)>,
+    timeout: Option<Duration>,
+) -> Fallible<()> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let timed_out = |proc: &mut Child| -> Fallible<()> {
+        proc.kill()?;
+        proc.wait()?;
+        bail!(
+            "Timed out pulling {} after {} seconds",
+            name,
+            timeout.map(|t| t.as_secs()).unwrap_or(0)
+        )
+    };
+    let mut c = cmd();
+    c.args(&["pull", name]);
+    if progress.is_some() {
+        c.stdout(Stdio::null()).stderr(Stdio::piped());
+    }
+    let mut proc = c.spawn()?;
+    if let Some(progress) = progress {
+        // Read in another thread so we can give up waiting for output
+        let serr = std::io::BufReader::new(proc.stderr.take().expect("stderr piped"));
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in serr.lines() {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        loop {
+            let line = match deadline {
+                Some(d) => rx.recv_timeout(d.saturating_duration_since(Instant::now())),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match line {
+                Ok(line) => progress(PullProgress::parse(&line?)),
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => return timed_out(&mut proc),
+            }
+        }
+    }
+    let status = loop {
+        if let Some(status) = proc.try_wait()? {
+            break status;
+        }
+        if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+            return timed_out(&mut proc);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    if !status.success() {
+        bail!("podman pull {} failed", name)
+    }
+    Ok(())
+}
+
+/// Build a `podman exec` of `argv` in the container; useful for
+/// callers that need to e.g. replace the current process.
+pub(crate) fn exec_command<S: AsRef<std::ffi::OsStr>>(
+    name: &str,
+    argv: &[S],
+    opts: &ExecOptions,
+) -> Command {
+    let mut c = cmd();
+    c.arg("exec");
+    if opts.interactive {
+        c.arg("--interactive");
+    }
+    if opts.tty {
+        c.arg("--tty");
+    }
+    for e in opts.env.iter() {
+        c.arg(format!("--env={}", e));
+    }
+    if let Some(workdir) = opts.workdir.as_ref() {
+        c.arg(format!("--workdir={}", workdir));
+    }
+    if let Some(user) = opts.user.as_ref() {
+        c.arg(format!("--user={}", user));
+    }
+    c.arg(name).args(argv);
+    c
+}
+
+/// Run a command in a container, returning its exit status.
+pub(crate) fn exec<S: AsRef<std::ffi::OsStr>>(
+    name: &str,
+    argv: &[S],
+    opts: &ExecOptions,
+) -> Fallible<ExitStatus> {
+    Ok(exec_command(name, argv, opts).status()?)
+}
+
+/// Run a command in a container, capturing its output.
+pub(crate) fn exec_output<S: AsRef<std::ffi::OsStr>>(
+    name: &str,
+    argv: &[S],
+    opts: &ExecOptions,
+) -> Fallible<Output> {
+    Ok(exec_command(name, argv, opts)
+        .stdin(Stdio::null())
+        .output()?)
+}
+
+/// The lines of podman's stderr that aren't logging below warning level,
+/// e.g. from `--log-level=debug`.
+fn error_lines(stderr: &str) -> String {
+    stderr
+        .lines()
+        .filter(|l| {
+            !["level=trace", "level=debug", "level=info"]
+                .iter()
+                .any(|level| l.contains(level))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// Returns true if a podman error is from contention on a lock, which is
+/// worth retrying.
+fn is_lock_contention(errors: &str) -> bool {
+    let errors = errors.to_lowercase();
+    [
+        "resource temporarily unavailable",
+        "device or resource busy",
+        "acquiring lock",
+    ]
+    .iter()
+    .any(|m| errors.contains(m))
+}
+
+/// Start a container.  Another podman process holding the storage lock can
+/// make this fail transiently, so retry those failures with a backoff.
+pub(crate) fn start(name: &str) -> Fallible<()> {
+    let retries = 3;
+    let mut delay = Duration::from_millis(250);
+    for attempt in 0.. {
+        let out = cmd()
+            .args(&["start", name])
+            .stdout(Stdio::null())
+            .output()?;
+        if out.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        let stderr = error_lines(&stderr);
+        let transient = is_lock_contention(&stderr);
+        if !transient || attempt == retries {
+            if transient {
+                bail!(
+                    "Starting {} failed after {} retries; another podman process may be holding the storage lock: {}",
+                    name,
+                    retries,
+                    stderr
+                );
+            }
+            bail!("Starting {} failed: {}", name, stderr);
+        }
+        eprintln!("podman start {}: storage busy; retrying", name);
+        std::thread::sleep(delay);
+        delay *= 2;
+    }
+    unreachable!()
+}
+
+/// Returns the names of all containers (running or not) matching the `--filter`s.
+pub(crate) fn container_names(filters: &[&str]) -> Fallible<Vec<String>> {
+    let mut c = cmd();
+    c.args(&["ps", "--all", "--format", "{{.Names}}"]);
+    for f in filters {
+        c.args(&["--filter", f]);
+    }
+    let out = c.stderr(Stdio::inherit()).output()?;
+    if !out.status.success() {
+        bail!("podman ps failed")
+    }
+    Ok(String::from_utf8(out.stdout)?
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// Inspect the named containers.
+pub(crate) fn container_inspect(names: &[String]) -> Fallible<Vec<ContainerInspect>> {
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let out = cmd()
+        .args(&["inspect", "--type", "container"])
+        .args(names)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        bail!("podman inspect failed")
+    }
+    Ok(serde_json::from_slice(&out.stdout)?)
+}
+
 pub(crate) fn image_inspect<I, S>(args: I) -> Fallible<Vec<ImageInspect>>
 where
     I: IntoIterator<Item = S>,
@@ -61,3 +529,94 @@ where
     }
     Ok(res)
 }
+
+/// Returns the digests, e.g. `sha256:...`, of a locally present image; the
+/// first is that of the manifest it was pulled with.
+pub(crate) fn image_digests(name: &str) -> Fallible<Vec<String>> {
+    let out = cmd()
+        .args(&[
+            "inspect",
+            "--type",
+            "image",
+            "--format",
+            "{{.Digest}}\n{{range .RepoDigests}}{{.}}\n{{end}}",
+            name,
+        ])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        bail!("podman inspect failed for image {}", name)
+    }
+    let mut r: Vec<String> = Vec::new();
+    for line in String::from_utf8(out.stdout)?.lines() {
+        // RepoDigests are `name@digest`
+        let digest = line.rsplit('@').next().unwrap_or("").trim();
+        if digest.starts_with("sha256:") && !r.iter().any(|d| d == digest) {
+            r.push(digest.to_string());
+        }
+    }
+    Ok(r)
+}
+
+/// Returns the full ID of a locally present image.
+pub(crate) fn image_id(name: &str) -> Fallible<String> {
+    let out = cmd()
+        .args(&["inspect", "--type", "image", "--format", "{{.Id}}", name])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        bail!("podman inspect failed for image {}", name)
+    }
+    Ok(String::from_utf8(out.stdout)?.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_name() {
+        let image = |names: Option<Vec<&str>>| ImageInspect {
+            id: "abc123".to_string(),
+            names: names.map(|n| n.into_iter().map(String::from).collect()),
+        };
+        assert_eq!(image(Some(vec!["a:1", "a:2"])).name(), "a:1");
+        assert_eq!(image(Some(vec![])).name(), "abc123");
+        assert_eq!(image(None).name(), "abc123");
+    }
+
+    #[test]
+    fn test_info_parse() {
+        let info = Info::parse(
+            br#"{"host": {"serviceIsRemote": false}, "store": {"graphDriverName": "overlay"}}"#,
+        )
+        .unwrap();
+        assert_eq!(info.storage_driver, "overlay");
+        // podman 1.x
+        let info = Info::parse(br#"{"host": {}, "store": {"GraphDriverName": "vfs"}}"#).unwrap();
+        assert_eq!(info.storage_driver, "vfs");
+        assert!(Info::parse(b"not json").is_err());
+    }
+
+    #[test]
+    fn test_lock_contention() {
+        let debug =
+            "time=\"2021-01-01T00:00:00Z\" level=debug msg=\"acquiring lock 1 for container\"\n\
+                     time=\"2021-01-01T00:00:00Z\" level=debug msg=\"block device busy\"";
+        let missing = format!(
+            "{}\nError: no container with name or ID \"foo\" found: no such container",
+            debug
+        );
+        assert!(!is_lock_contention(&error_lines(&missing)));
+        assert_eq!(
+            error_lines(&missing),
+            "Error: no container with name or ID \"foo\" found: no such container"
+        );
+        let locked = format!(
+            "{}\nError: acquiring lock 1 for container abc: resource temporarily unavailable",
+            debug
+        );
+        assert!(is_lock_contention(&error_lines(&locked)));
+        assert!(!is_lock_contention("Error: clock skew detected"));
+    }
+}